@@ -0,0 +1,241 @@
+use crate::{
+    desktop::DesktopIndex,
+    dunstrc,
+    icons::IconFormat,
+    markup::MarkupMode,
+    output::{OutputMode, Sink, SinkConfig},
+    rules::{Rule, RuleConfig},
+    template::Template,
+    Args, CloseReason,
+};
+use serde::Deserialize;
+use std::{
+    env,
+    error::Error,
+    fs::{create_dir_all, read_to_string},
+    path::Path,
+};
+
+/// Settings read from the configuration file. Everything is optional since the command line
+/// arguments take precedence over it.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+struct FileConfig {
+    length: Option<usize>,
+    themes: Vec<String>,
+    fallback_icon: Option<String>,
+    cache_dir: Option<String>,
+    history_file: Option<String>,
+    keep_reasons: Vec<CloseReason>,
+    keep_transient: Option<bool>,
+    copy_icon_files: Option<bool>,
+    icon_size: Option<u16>,
+    icon_scale: Option<u16>,
+    icon_format: Option<IconFormat>,
+    icon_quality: Option<u8>,
+    keep_svg: Option<bool>,
+    cache_theme_icons: Option<bool>,
+    ignore_dunst_rules: Option<bool>,
+    markup: Option<MarkupMode>,
+    socket: Option<String>,
+    output: Option<OutputMode>,
+    eww_variable: Option<String>,
+    format_template: Option<String>,
+    track_active: Option<bool>,
+    rules: Vec<RuleConfig>,
+    sinks: Vec<SinkConfig>,
+}
+
+/// Settings shared by all message handlers
+#[derive(Debug)]
+pub struct Config {
+    /// Length of the history, where 0 means unlimited like it does for dunst
+    pub length: usize,
+    pub themes: Vec<String>,
+    pub fallback_icon: Option<String>,
+    pub cache_dir: String,
+    pub history_file: String,
+    pub keep_reasons: Vec<CloseReason>,
+    pub keep_transient: bool,
+    pub copy_icon_files: bool,
+    pub icon_size: Option<u16>,
+    pub icon_scale: u16,
+    pub icon_format: IconFormat,
+    pub icon_quality: Option<u8>,
+    pub keep_svg: bool,
+    pub cache_theme_icons: bool,
+    pub rules: Vec<Rule>,
+    pub markup: MarkupMode,
+    pub socket: String,
+    pub sinks: Vec<Sink>,
+    pub track_active: bool,
+    pub desktop_icons: DesktopIndex,
+}
+
+pub fn config_home() -> String {
+    match env::var("XDG_CONFIG_HOME") {
+        Ok(d) if !d.is_empty() => d,
+        _ => format!("{}/.config", env::var("HOME").unwrap_or_default()),
+    }
+}
+
+fn config_path(args: &Args) -> String {
+    match &args.config {
+        Some(p) => p.clone(),
+        None => format!("{}/disgustang/config.toml", config_home()),
+    }
+}
+
+fn default_socket() -> String {
+    match env::var("XDG_RUNTIME_DIR") {
+        Ok(d) if !d.is_empty() => format!("{}/disgustang.sock", d),
+        _ => format!(
+            "/tmp/disgustang-{}.sock",
+            env::var("USER").unwrap_or_default()
+        ),
+    }
+}
+
+/// Only the path of the control socket, for talking to a running instance without loading and
+/// checking the rest of the configuration
+pub fn socket(args: &Args) -> Result<String, Box<dyn Error>> {
+    if let Some(s) = &args.socket {
+        return Ok(s.clone());
+    }
+    let path = config_path(args);
+    if Path::new(&path).exists() {
+        let table: toml::Table = match toml::from_str(&read_to_string(&path)?) {
+            Ok(t) => t,
+            Err(err) => return Err(format!("Failed parsing {}: {}", path, err).into()),
+        };
+        if let Some(s) = table.get("socket").and_then(|s| s.as_str()) {
+            return Ok(s.to_string());
+        }
+    }
+    Ok(default_socket())
+}
+
+impl Config {
+    /// How many entries a history of the given length has to drop to fit the configured length
+    pub fn history_excess(&self, len: usize) -> usize {
+        match self.length {
+            0 => 0,
+            length => len.saturating_sub(length),
+        }
+    }
+
+    /// Read the configuration file and apply the command line arguments on top of it
+    pub fn load(args: &Args) -> Result<Config, Box<dyn Error>> {
+        let path = config_path(args);
+        let file: FileConfig = if Path::new(&path).exists() {
+            match toml::from_str(&read_to_string(&path)?) {
+                Ok(f) => f,
+                Err(err) => return Err(format!("Failed parsing {}: {}", path, err).into()),
+            }
+        } else if args.config.is_some() {
+            return Err(format!("Configuration file {} does not exist", path).into());
+        } else {
+            FileConfig::default()
+        };
+
+        let cache_dir = match args.cache_dir.clone().or(file.cache_dir) {
+            Some(d) => d,
+            None => format!(
+                "{}/{}",
+                env::var("XDG_CACHE_HOME").unwrap_or(String::from(".")),
+                "disgustang"
+            ),
+        };
+        if !Path::new(&cache_dir).exists() && create_dir_all(&cache_dir).is_err() {
+            return Err(format!("Failed creating cache directory {}", cache_dir).into());
+        }
+        let history_file = match args.history_file.clone().or(file.history_file) {
+            Some(f) => f,
+            None => format!("{}/{}", cache_dir, "history.json"),
+        };
+
+        let themes = if !args.theme.is_empty() {
+            args.theme.clone()
+        } else if !file.themes.is_empty() {
+            file.themes
+        } else {
+            crate::icons::gtk_icon_theme().into_iter().collect()
+        };
+
+        let dunst = dunstrc::load();
+        let mut rules = Vec::new();
+        if !(args.ignore_dunst_rules || file.ignore_dunst_rules.unwrap_or(false)) {
+            rules.extend(dunstrc::rules(&dunst));
+        }
+        for rule in file.rules {
+            rules.push(Rule::try_from(rule)?);
+        }
+
+        // The output options on the command line describe a single sink replacing any in the file
+        let cli_output =
+            args.output.is_some() || args.eww_variable.is_some() || args.format_template.is_some();
+        let mut sinks = Vec::new();
+        if cli_output || file.sinks.is_empty() {
+            let output = args.output.or(file.output).unwrap_or(OutputMode::Full);
+            let template = args
+                .format_template
+                .clone()
+                .or(file.format_template)
+                .map(|t| t.parse::<Template>())
+                .transpose()?;
+            if output == OutputMode::NdjsonEvents && template.is_some() {
+                return Err("A format template can't be used with the ndjson-events output".into());
+            }
+            sinks.push(Sink::from_mode(
+                output,
+                &args
+                    .eww_variable
+                    .clone()
+                    .or(file.eww_variable)
+                    .unwrap_or(String::from("notifications")),
+                template,
+            ));
+        } else {
+            for sink in file.sinks {
+                sinks.push(Sink::try_from(sink)?);
+            }
+        }
+
+        Ok(Config {
+            length: match args.length.or(file.length) {
+                Some(l) => l,
+                None => dunstrc::history_length(&dunst).unwrap_or(dunstrc::DEFAULT_HISTORY_LENGTH),
+            },
+            themes,
+            fallback_icon: args.fallback_icon.clone().or(file.fallback_icon),
+            cache_dir,
+            history_file,
+            keep_reasons: if args.keep_reasons.is_empty() {
+                file.keep_reasons
+            } else {
+                args.keep_reasons.clone()
+            },
+            keep_transient: args.keep_transient || file.keep_transient.unwrap_or(false),
+            copy_icon_files: args.copy_icon_files || file.copy_icon_files.unwrap_or(false),
+            icon_size: args.icon_size.or(file.icon_size),
+            icon_scale: args.icon_scale.or(file.icon_scale).unwrap_or(1).max(1),
+            icon_format: args
+                .icon_format
+                .or(file.icon_format)
+                .unwrap_or(IconFormat::Png),
+            icon_quality: args.icon_quality.or(file.icon_quality),
+            keep_svg: args.keep_svg || file.keep_svg.unwrap_or(false),
+            cache_theme_icons: args.cache_theme_icons || file.cache_theme_icons.unwrap_or(false),
+            rules,
+            markup: args.markup.or(file.markup).unwrap_or(MarkupMode::Strip),
+            socket: args
+                .socket
+                .clone()
+                .or(file.socket)
+                .unwrap_or_else(default_socket),
+            sinks,
+            track_active: args.track_active || file.track_active.unwrap_or(false),
+            desktop_icons: DesktopIndex::build(),
+        })
+    }
+}
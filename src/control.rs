@@ -0,0 +1,172 @@
+use std::{
+    error::Error,
+    fs::{remove_file, symlink_metadata},
+    os::unix::fs::FileTypeExt,
+    str::FromStr,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::{mpsc, oneshot},
+};
+
+/// Commands accepted on the control socket, one per line
+#[derive(Debug)]
+pub enum Request {
+    /// The whole history, newest first
+    History,
+    /// A single notification from the history
+    Get(u32),
+    Remove(u32),
+    Clear,
+    MarkRead(u32),
+    /// Emit ActionInvoked for a notification in the history with the given action key
+    InvokeAction(u32, String),
+}
+
+impl FromStr for Request {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let mut id = || -> Result<u32, String> {
+            match words.next().map(str::parse) {
+                Some(Ok(id)) => Ok(id),
+                _ => Err(format!("{} needs a notification ID", command)),
+            }
+        };
+        match command {
+            "history" => Ok(Request::History),
+            "get" => Ok(Request::Get(id()?)),
+            "remove" => Ok(Request::Remove(id()?)),
+            "clear" => Ok(Request::Clear),
+            "read" => Ok(Request::MarkRead(id()?)),
+            "invoke" => {
+                let id = id()?;
+                // The key is the rest of the line since keys may contain spaces
+                let rest = line.trim_start()[command.len()..].trim_start();
+                match rest.split_once(char::is_whitespace) {
+                    Some((_, key)) if !key.trim().is_empty() => {
+                        Ok(Request::InvokeAction(id, key.trim_start().to_string()))
+                    }
+                    _ => Err(String::from("invoke needs an action key")),
+                }
+            }
+            _ => Err(format!("Unknown command {}", command)),
+        }
+    }
+}
+
+/// A request along with where to send the reply. Replies are JSON on success.
+pub type Message = (Request, oneshot::Sender<Result<String, String>>);
+
+async fn handle_client(
+    stream: UnixStream,
+    requests: mpsc::Sender<Message>,
+) -> Result<(), Box<dyn Error>> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let reply = match line.parse::<Request>() {
+            Ok(request) => {
+                let (tx, rx) = oneshot::channel();
+                requests.send((request, tx)).await?;
+                rx.await?
+            }
+            Err(err) => Err(err),
+        };
+        let reply = match reply {
+            Ok(json) => json,
+            Err(err) => format!("error: {}", err),
+        };
+        writer.write_all(format!("{}\n", reply).as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Accept connections on the control socket, passing their requests on to the main loop
+pub fn listen(path: &str, requests: mpsc::Sender<Message>) -> Result<(), Box<dyn Error>> {
+    // A socket left behind by a previous run would make binding fail, but one that still accepts
+    // connections belongs to another instance
+    if let Ok(metadata) = symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err("The path exists and isn't a socket".into());
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err("Another instance is already listening".into());
+        }
+        remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let requests = requests.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = handle_client(stream, requests).await {
+                            eprintln!("Control socket client failed: {}", err);
+                        }
+                    });
+                }
+                Err(err) => eprintln!("Failed accepting control socket connection: {}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Send a single command to a running instance and return its reply
+pub async fn send(path: &str, command: &str) -> Result<String, Box<dyn Error>> {
+    let stream = match UnixStream::connect(path).await {
+        Ok(s) => s,
+        Err(err) => return Err(format!("Failed connecting to {}: {}", path, err).into()),
+    };
+    let (reader, mut writer) = stream.into_split();
+    writer
+        .write_all(format!("{}\n", command).as_bytes())
+        .await?;
+    writer.shutdown().await?;
+
+    let reply = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .unwrap_or_default();
+    match reply.strip_prefix("error: ") {
+        Some(err) => Err(err.to_string().into()),
+        None => Ok(reply),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_requests() {
+        assert!(matches!("history".parse(), Ok(Request::History)));
+        assert!(matches!(" read  4 ".parse(), Ok(Request::MarkRead(4))));
+        assert_eq!(
+            "remove x".parse::<Request>().unwrap_err(),
+            "remove needs a notification ID"
+        );
+        assert_eq!(
+            "nope".parse::<Request>().unwrap_err(),
+            "Unknown command nope"
+        );
+    }
+
+    #[test]
+    fn action_keys_keep_their_spaces() {
+        match "invoke 3  open  in browser".parse() {
+            Ok(Request::InvokeAction(3, key)) => assert_eq!(key, "open  in browser"),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(
+            "invoke 3 ".parse::<Request>().unwrap_err(),
+            "invoke needs an action key"
+        );
+    }
+}
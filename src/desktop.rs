@@ -0,0 +1,107 @@
+use std::{
+    collections::HashMap,
+    env,
+    ffi::OsStr,
+    fs::{read_dir, read_to_string},
+    path::{Path, PathBuf},
+};
+
+/// Directories to search for .desktop files, in order of preference
+fn application_dirs() -> Vec<PathBuf> {
+    let mut dirs = vec![match env::var("XDG_DATA_HOME") {
+        Ok(d) if !d.is_empty() => d,
+        _ => format!("{}/.local/share", env::var("HOME").unwrap_or_default()),
+    }];
+    match env::var("XDG_DATA_DIRS") {
+        Ok(d) if !d.is_empty() => dirs.extend(d.split(':').map(String::from)),
+        _ => dirs.extend([String::from("/usr/local/share"), String::from("/usr/share")]),
+    }
+    dirs.iter()
+        .map(|d| Path::new(d).join("applications"))
+        .collect()
+}
+
+/// Read a key from the [Desktop Entry] group of a .desktop file
+fn desktop_entry_value(contents: &str, key: &str) -> Option<String> {
+    let mut in_entry = false;
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_entry = line == "[Desktop Entry]";
+        } else if in_entry {
+            if let Some((k, v)) = line.split_once('=') {
+                if k.trim() == key {
+                    return Some(v.trim().to_string());
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Find the Icon= of the .desktop file named by the desktop-entry hint
+fn icon_by_desktop_entry(dirs: &[PathBuf], desktop_entry: &str) -> Option<String> {
+    let name = desktop_entry.trim_end_matches(".desktop");
+    dirs.iter().find_map(|dir| {
+        let contents = read_to_string(dir.join(format!("{}.desktop", name))).ok()?;
+        desktop_entry_value(&contents, "Icon")
+    })
+}
+
+/// Icons of the installed applications by the lowercased Name= and StartupWMClass= of their
+/// .desktop files, built once since reading every .desktop file per notification is slow
+#[derive(Debug)]
+pub struct DesktopIndex {
+    dirs: Vec<PathBuf>,
+    by_appname: HashMap<String, String>,
+}
+
+impl DesktopIndex {
+    pub fn build() -> DesktopIndex {
+        let dirs = application_dirs();
+        let mut by_appname = HashMap::new();
+        for dir in dirs.iter() {
+            let entries = match read_dir(dir) {
+                Ok(e) => e,
+                Err(_) => continue,
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.extension() != Some(OsStr::new("desktop")) {
+                    continue;
+                }
+                let contents = match read_to_string(&path) {
+                    Ok(c) => c,
+                    Err(_) => continue,
+                };
+                let icon = match desktop_entry_value(&contents, "Icon") {
+                    Some(i) => i,
+                    None => continue,
+                };
+                // Earlier directories take precedence
+                for key in ["Name", "StartupWMClass"] {
+                    if let Some(name) = desktop_entry_value(&contents, key) {
+                        by_appname
+                            .entry(name.to_ascii_lowercase())
+                            .or_insert_with(|| icon.clone());
+                    }
+                }
+            }
+        }
+        DesktopIndex { dirs, by_appname }
+    }
+
+    /// Find the icon of the application that sent a notification through its .desktop file,
+    /// either by the desktop-entry hint or by matching the appname
+    pub fn find_icon(&self, desktop_entry: Option<&str>, appname: &str) -> Option<String> {
+        if let Some(entry) = desktop_entry.filter(|x| !x.is_empty()) {
+            if let Some(icon) = icon_by_desktop_entry(&self.dirs, entry) {
+                return Some(icon);
+            }
+        }
+        if appname.is_empty() {
+            return None;
+        }
+        self.by_appname.get(&appname.to_ascii_lowercase()).cloned()
+    }
+}
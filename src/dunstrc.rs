@@ -0,0 +1,287 @@
+use crate::{config::config_home, rules::Rule};
+use regex::Regex;
+use std::{
+    env,
+    fs::{read_dir, read_to_string},
+    path::{Path, PathBuf},
+};
+
+/// History length dunst uses when none is configured
+pub const DEFAULT_HISTORY_LENGTH: usize = 20;
+
+/// A [section] of dunstrc with its keys in the order they appear
+#[derive(Debug)]
+pub struct Section {
+    pub name: String,
+    pub entries: Vec<(String, String)>,
+}
+
+impl Section {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        // Later keys override earlier ones just like in dunst
+        self.entries
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Directories dunst searches for its configuration, in order of preference
+fn config_dirs() -> Vec<PathBuf> {
+    let mut dirs = vec![PathBuf::from(config_home())];
+    match env::var("XDG_CONFIG_DIRS") {
+        Ok(d) if !d.is_empty() => dirs.extend(d.split(':').map(PathBuf::from)),
+        _ => dirs.push(PathBuf::from("/etc/xdg")),
+    }
+    dirs.iter().map(|d| d.join("dunst")).collect()
+}
+
+/// Strip quotes and trailing comments from a value
+fn parse_value(value: &str) -> String {
+    let value = value.trim();
+    if let Some(quoted) = value.strip_prefix('"') {
+        return match quoted.find('"') {
+            Some(end) => quoted[..end].to_string(),
+            None => quoted.to_string(),
+        };
+    }
+    match value.find(['#', ';']) {
+        Some(pos) => value[..pos].trim_end().to_string(),
+        None => value.to_string(),
+    }
+}
+
+fn parse(contents: &str, sections: &mut Vec<Section>) {
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            sections.push(Section {
+                name: name.trim().to_string(),
+                entries: Vec::new(),
+            });
+        } else if let Some((key, value)) = line.split_once('=') {
+            if let Some(section) = sections.last_mut() {
+                section
+                    .entries
+                    .push((key.trim().to_string(), parse_value(value)));
+            }
+        }
+    }
+}
+
+/// Read dunstrc and its dunstrc.d/*.conf drop-ins in the order dunst applies them
+pub fn load() -> Vec<Section> {
+    let mut sections = Vec::new();
+    let dir = match config_dirs()
+        .into_iter()
+        .find(|d| d.join("dunstrc").exists() || d.join("dunstrc.d").is_dir())
+    {
+        Some(d) => d,
+        None => return sections,
+    };
+
+    if let Ok(contents) = read_to_string(dir.join("dunstrc")) {
+        parse(&contents, &mut sections);
+    }
+    if let Ok(entries) = read_dir(dir.join("dunstrc.d")) {
+        let mut dropins: Vec<PathBuf> = entries
+            .flatten()
+            .map(|e| e.path())
+            .filter(|p| p.extension().is_some_and(|e| e == "conf"))
+            .collect();
+        dropins.sort();
+        for path in dropins.iter() {
+            if let Ok(contents) = read_to_string(Path::new(path)) {
+                parse(&contents, &mut sections);
+            }
+        }
+    }
+    sections
+}
+
+/// The history_length of the [global] section. Since drop-ins may repeat sections, the last one
+/// setting it wins.
+pub fn history_length(sections: &[Section]) -> Option<usize> {
+    sections
+        .iter()
+        .rev()
+        .filter(|s| s.name == "global")
+        .find_map(|s| s.get("history_length"))
+        .and_then(|v| v.parse().ok())
+}
+
+/// Sections of dunstrc that configure dunst itself rather than being rules
+const RESERVED_SECTIONS: [&str; 7] = [
+    "global",
+    "experimental",
+    "urgency_low",
+    "urgency_normal",
+    "urgency_critical",
+    "frame",
+    "shortcuts",
+];
+
+fn parse_bool(value: &str) -> bool {
+    matches!(
+        value.to_ascii_lowercase().as_str(),
+        "true" | "yes" | "on" | "1"
+    )
+}
+
+fn parse_urgency(value: &str) -> Option<u8> {
+    match value.to_ascii_lowercase().as_str() {
+        "low" => Some(0),
+        "normal" => Some(1),
+        "critical" => Some(2),
+        _ => None,
+    }
+}
+
+/// Translate a shell glob as used by fnmatch into an anchored regular expression
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut in_class = false;
+    for c in glob.chars() {
+        match c {
+            '*' if !in_class => regex.push_str(".*"),
+            '?' if !in_class => regex.push('.'),
+            '[' if !in_class => {
+                in_class = true;
+                regex.push('[');
+            }
+            ']' if in_class => {
+                in_class = false;
+                regex.push(']');
+            }
+            '!' if in_class && regex.ends_with('[') => regex.push('^'),
+            _ if in_class => regex.push(c),
+            _ => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+/// Turn the rule sections of dunstrc into rules. Dunst matches with globs unless enable_regex is
+/// set in [global], in which case the patterns are unanchored regular expressions.
+pub fn rules(sections: &[Section]) -> Vec<Rule> {
+    let enable_regex = sections
+        .iter()
+        .rev()
+        .filter(|s| s.name == "global")
+        .find_map(|s| s.get("enable_regex"))
+        .is_some_and(parse_bool);
+
+    let mut rules = Vec::new();
+    for section in sections
+        .iter()
+        .filter(|s| !RESERVED_SECTIONS.contains(&s.name.as_str()))
+    {
+        let mut invalid = false;
+        let mut pattern = |key: &str| -> Option<Regex> {
+            let value = section.get(key)?;
+            let source = if enable_regex {
+                value.to_string()
+            } else {
+                glob_to_regex(value)
+            };
+            match Regex::new(&source) {
+                Ok(r) => Some(r),
+                Err(err) => {
+                    eprintln!(
+                        "Ignoring dunst rule [{}] with invalid {}: {}",
+                        section.name, key, err
+                    );
+                    invalid = true;
+                    None
+                }
+            }
+        };
+        let rule = Rule {
+            appname: pattern("appname"),
+            summary: pattern("summary"),
+            body: pattern("body"),
+            category: pattern("category"),
+            desktop_entry: pattern("desktop_entry"),
+            icon: pattern("icon"),
+            // In dunst urgency sets the urgency while msg_urgency matches on it
+            urgency: section.get("msg_urgency").and_then(parse_urgency),
+            drop: false,
+            skip_history: section.get("history_ignore").is_some_and(parse_bool),
+            skip_display: section.get("skip_display").is_some_and(parse_bool),
+            new_icon: section.get("new_icon").map(String::from),
+            set_urgency: section.get("urgency").and_then(parse_urgency),
+            set_stack_tag: section.get("set_stack_tag").map(String::from),
+            format: section.get("format").map(String::from),
+        };
+        if !invalid {
+            rules.push(rule);
+        }
+    }
+    rules
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_value_strips_quotes_and_comments() {
+        assert_eq!(parse_value("  plain  "), "plain");
+        assert_eq!(parse_value("value # comment"), "value");
+        assert_eq!(parse_value("value; comment"), "value");
+        assert_eq!(
+            parse_value("\"quoted # not a comment\" # comment"),
+            "quoted # not a comment"
+        );
+        assert_eq!(parse_value("\"unterminated"), "unterminated");
+        assert_eq!(parse_value(""), "");
+    }
+
+    #[test]
+    fn parse_collects_sections_in_order() {
+        let mut sections = Vec::new();
+        parse(
+            "# comment\nignored = before any section\n[global]\n  history_length = 5\n; another\n\n[rule]\nappname = foo\nappname=bar\n",
+            &mut sections,
+        );
+        assert_eq!(sections.len(), 2);
+        assert_eq!(sections[0].name, "global");
+        assert_eq!(sections[0].get("history_length"), Some("5"));
+        assert_eq!(sections[1].name, "rule");
+        assert_eq!(sections[1].get("appname"), Some("bar"));
+        assert_eq!(sections[1].get("summary"), None);
+    }
+
+    #[test]
+    fn history_length_prefers_the_last_global_section() {
+        let mut sections = Vec::new();
+        parse("[global]\nhistory_length = 5\n", &mut sections);
+        parse(
+            "[global]\nhistory_length = 7\n[global]\nfont = x\n",
+            &mut sections,
+        );
+        assert_eq!(history_length(&sections), Some(7));
+        assert_eq!(history_length(&[]), None);
+    }
+
+    #[test]
+    fn glob_to_regex_matches_like_fnmatch() {
+        let matches =
+            |glob: &str, text: &str| Regex::new(&glob_to_regex(glob)).unwrap().is_match(text);
+        assert!(matches("*", ""));
+        assert!(matches("fire*", "firefox"));
+        assert!(!matches("fire*", "a firefox"));
+        assert!(matches("f?x", "fox"));
+        assert!(!matches("f?x", "fx"));
+        assert!(matches("[ab]c", "bc"));
+        assert!(!matches("[!ab]c", "bc"));
+        assert!(matches("[!ab]c", "xc"));
+        assert!(matches("a.b+(c)", "a.b+(c)"));
+        assert!(!matches("a.b", "axb"));
+    }
+}
@@ -0,0 +1,437 @@
+use crate::{config::config_home, Config};
+use clap::ValueEnum;
+use crypto::{digest::Digest, sha1::Sha1};
+use freedesktop_icons::lookup;
+use gdk_pixbuf::{glib::Bytes, Colorspace, InterpType, Pixbuf};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs::{copy, read, read_to_string, remove_file},
+    path::Path,
+    process::Command,
+};
+use zbus::zvariant::{Structure, Value};
+
+/// Image format of cached icons
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum IconFormat {
+    Png,
+    Jpeg,
+    Webp,
+}
+
+impl IconFormat {
+    /// Name of the gdk-pixbuf saver for this format
+    fn name(&self) -> &'static str {
+        match self {
+            IconFormat::Png => "png",
+            IconFormat::Jpeg => "jpeg",
+            IconFormat::Webp => "webp",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            IconFormat::Png => "png",
+            IconFormat::Jpeg => "jpg",
+            IconFormat::Webp => "webp",
+        }
+    }
+
+    /// Saver options for the given quality. For PNG this is the compression level (0-9),
+    /// otherwise the quality (0-100).
+    fn options(&self, quality: Option<u8>) -> Vec<(&'static str, String)> {
+        match (self, quality) {
+            (_, None) => Vec::new(),
+            (IconFormat::Png, Some(q)) => vec![("compression", q.min(9).to_string())],
+            (_, Some(q)) => vec![("quality", q.min(100).to_string())],
+        }
+    }
+}
+
+/// Make sure gdk-pixbuf is able to save icons in the given format
+pub fn check_format(format: IconFormat) -> Result<(), Box<dyn Error>> {
+    if Pixbuf::formats()
+        .iter()
+        .any(|f| f.name() == format.name() && f.is_writable())
+    {
+        Ok(())
+    } else {
+        Err(format!(
+            "gdk-pixbuf has no loader able to save {} images",
+            format.name()
+        )
+        .into())
+    }
+}
+
+/// Read the icon theme configured for GTK, first through gsettings and then settings.ini
+pub fn gtk_icon_theme() -> Option<String> {
+    if let Ok(output) = Command::new("gsettings")
+        .args(["get", "org.gnome.desktop.interface", "icon-theme"])
+        .output()
+    {
+        let theme = String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .trim_matches('\'')
+            .to_string();
+        if output.status.success() && !theme.is_empty() {
+            return Some(theme);
+        }
+    }
+
+    let config_home = config_home();
+    ["gtk-4.0", "gtk-3.0"].iter().find_map(|gtk| {
+        let contents = read_to_string(format!("{}/{}/settings.ini", config_home, gtk)).ok()?;
+        contents.lines().find_map(|line| {
+            let (key, value) = line.split_once('=')?;
+            if key.trim() == "gtk-icon-theme-name" {
+                Some(value.trim().trim_matches('"').to_string())
+            } else {
+                None
+            }
+        })
+    })
+}
+
+/// Look up an icon in each of the configured themes in order
+pub fn lookup_icon(config: &Config, name: &str) -> String {
+    let find = |theme: Option<&str>| {
+        let mut builder = lookup(name).with_cache();
+        // Leave freedesktop-icons' own defaults alone unless asked otherwise
+        if let Some(size) = config.icon_size {
+            builder = builder.with_size(size);
+        }
+        if config.icon_scale != 1 {
+            builder = builder.with_scale(config.icon_scale);
+        }
+        match theme {
+            Some(t) => builder.with_theme(t).find(),
+            None => builder.find(),
+        }
+    };
+    // Every lookup falls back to hicolor and pixmaps on its own, so those hits only count once
+    // none of the themes has the icon itself
+    let is_fallback = |path: &Path, theme: &str| {
+        theme != "hicolor"
+            && path
+                .components()
+                .any(|c| c.as_os_str() == "hicolor" || c.as_os_str() == "pixmaps")
+    };
+    let found = if config.themes.is_empty() {
+        find(None)
+    } else {
+        let mut fallback = None;
+        let mut found = None;
+        for theme in config.themes.iter() {
+            match find(Some(theme)) {
+                Some(p) if is_fallback(&p, theme) => {
+                    fallback.get_or_insert(p);
+                }
+                Some(p) => {
+                    found = Some(p);
+                    break;
+                }
+                None => (),
+            }
+        }
+        found.or(fallback)
+    };
+    match found {
+        Some(s) => s.into_os_string().into_string().unwrap_or(String::new()),
+        None => String::new(),
+    }
+}
+
+/// Drop a reference to a cached icon, deleting it once nothing uses it anymore
+pub fn release_icon(cached_icons: &mut HashMap<String, i64>, icon_path: &str) {
+    match cached_icons.get_mut(icon_path) {
+        None => (),
+        Some(e) => {
+            *e -= 1;
+            if *e <= 0 {
+                if remove_file(icon_path).is_err() {
+                    eprintln!("Failed removing file {}", icon_path);
+                }
+                cached_icons.remove(icon_path);
+            }
+        }
+    }
+}
+
+/// Turn a file:// URI or absolute path into a path, or None if it's neither
+pub fn uri_to_path(uri: &str) -> Option<String> {
+    let path = match uri.strip_prefix("file://") {
+        Some(p) => {
+            // Undo the percent-encoding of the URI
+            let bytes = p.as_bytes();
+            let mut decoded = Vec::with_capacity(bytes.len());
+            let mut i = 0;
+            while i < bytes.len() {
+                if bytes[i] == b'%' && i + 2 < bytes.len() {
+                    let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+                    if let Ok(b) = u8::from_str_radix(hex, 16) {
+                        decoded.push(b);
+                        i += 3;
+                        continue;
+                    }
+                }
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+            String::from_utf8(decoded).ok()?
+        }
+        None => uri.to_string(),
+    };
+    if path.starts_with('/') {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+/// Path in the cache for an image with the given hash. The configured size is part of the hash
+/// so changing it doesn't pick up icons cached at the old size.
+fn cache_path(mut hasher: Sha1, config: &Config) -> String {
+    if let Some(size) = config.icon_size {
+        hasher.input(format!("{}@{}", size, config.icon_scale).as_bytes());
+    }
+    if let Some(quality) = config.icon_quality {
+        hasher.input(format!("{}:{}", config.icon_format.name(), quality).as_bytes());
+    }
+    format!(
+        "{}/{}.{}",
+        config.cache_dir,
+        hasher.result_str(),
+        config.icon_format.extension()
+    )
+}
+
+/// Save an image into the cache in the configured format
+fn save_pixbuf(pixbuf: Pixbuf, path: &str, config: &Config) -> Result<(), Box<dyn Error>> {
+    let options = config.icon_format.options(config.icon_quality);
+    let options: Vec<(&str, &str)> = options.iter().map(|(k, v)| (*k, v.as_str())).collect();
+    scale_pixbuf(pixbuf, config).savev(path, config.icon_format.name(), &options)?;
+    Ok(())
+}
+
+/// Scale an image to fit the configured icon size while keeping its aspect ratio
+fn scale_pixbuf(pixbuf: Pixbuf, config: &Config) -> Pixbuf {
+    let size = match config.icon_size {
+        Some(s) => i32::from(s) * i32::from(config.icon_scale),
+        None => return pixbuf,
+    };
+    let (width, height) = (pixbuf.width(), pixbuf.height());
+    if width <= 0 || height <= 0 || width.max(height) == size {
+        return pixbuf;
+    }
+    let (width, height) = if width >= height {
+        (size, (height * size / width).max(1))
+    } else {
+        ((width * size / height).max(1), size)
+    };
+    match pixbuf.scale_simple(width, height, InterpType::Bilinear) {
+        Some(p) => p,
+        None => pixbuf,
+    }
+}
+
+/// Save raw image data from an (iiibiiay) structure into the cache
+fn cache_image_data(
+    img: &Structure,
+    cached_icons: &mut HashMap<String, i64>,
+    config: &Config,
+) -> Result<String, Box<dyn Error>> {
+    let img_fields = img.fields();
+    let bytes = if let Value::Array(a) = img_fields[6].clone() {
+        Bytes::from(
+            &(a.get()
+                .iter()
+                .map(|x| u8::try_from(x).unwrap())
+                .collect::<Vec<u8>>()),
+        )
+    } else {
+        Bytes::from_static(&[])
+    };
+    let mut hasher = Sha1::new();
+    hasher.input(&bytes);
+    let path = cache_path(hasher, config);
+    if !Path::new(&path).exists() {
+        let pixbuf = Pixbuf::from_bytes(
+            &bytes,
+            Colorspace::Rgb,
+            bool::try_from(img_fields[3].clone())?,
+            i32::try_from(img_fields[4].clone())?,
+            i32::try_from(img_fields[0].clone())?,
+            i32::try_from(img_fields[1].clone())?,
+            i32::try_from(img_fields[2].clone())?,
+        );
+        save_pixbuf(pixbuf, &path, config)?;
+    }
+    *cached_icons.entry(path.clone()).or_insert(0) += 1;
+    Ok(path)
+}
+
+/// Copy an image file into the cache so it outlives the original
+pub fn cache_image_file(
+    source: &str,
+    cached_icons: &mut HashMap<String, i64>,
+    config: &Config,
+) -> Result<String, Box<dyn Error>> {
+    let mut hasher = Sha1::new();
+    hasher.input(&read(source)?);
+
+    // SVGs scale on their own so they can be copied as they are
+    if config.keep_svg && Path::new(source).extension().is_some_and(|e| e == "svg") {
+        let path = format!("{}/{}.svg", config.cache_dir, hasher.result_str());
+        if !Path::new(&path).exists() {
+            copy(source, &path)?;
+        }
+        *cached_icons.entry(path.clone()).or_insert(0) += 1;
+        return Ok(path);
+    }
+
+    let path = cache_path(hasher, config);
+    if !Path::new(&path).exists() {
+        // Loading at the final size lets vector icons render sharply instead of being scaled up
+        let pixbuf = match config.icon_size {
+            Some(s) => {
+                let size = i32::from(s) * i32::from(config.icon_scale);
+                Pixbuf::from_file_at_scale(source, size, size, true)?
+            }
+            None => Pixbuf::from_file(source)?,
+        };
+        save_pixbuf(pixbuf, &path, config)?;
+    }
+    *cached_icons.entry(path.clone()).or_insert(0) += 1;
+    Ok(path)
+}
+
+/// Look up an icon in the theme, copying it into the cache if configured to
+pub fn theme_icon(name: &str, cached_icons: &mut HashMap<String, i64>, config: &Config) -> String {
+    let path = lookup_icon(config, name);
+    if path.is_empty() || !config.cache_theme_icons {
+        return path;
+    }
+    match cache_image_file(&path, cached_icons, config) {
+        Ok(cached) => cached,
+        Err(err) => {
+            eprintln!("Failed caching icon {}: {}", path, err);
+            path
+        }
+    }
+}
+
+/// Resolve an icon name, absolute path or file:// URI to an icon file
+pub fn resolve_icon_name(
+    name: &str,
+    cached_icons: &mut HashMap<String, i64>,
+    config: &Config,
+) -> String {
+    if name.is_empty() {
+        return String::new();
+    }
+    match uri_to_path(name) {
+        Some(path) => {
+            if !Path::new(&path).exists() {
+                return String::new();
+            }
+            if !config.copy_icon_files {
+                return path;
+            }
+            match cache_image_file(&path, cached_icons, config) {
+                Ok(cached) => cached,
+                Err(err) => {
+                    eprintln!("Failed caching image {}: {}", path, err);
+                    path
+                }
+            }
+        }
+        None => theme_icon(name, cached_icons, config),
+    }
+}
+
+/// Resolve the icon of a notification following the priority order of the spec: image-data,
+/// image-path, app_icon and finally icon_data. The deprecated underscore spellings are accepted
+/// alongside the current ones. See [`last_resort_icon`] for when none of these work out.
+pub fn resolve_icon(
+    dict: &Value,
+    app_icon: &str,
+    appname: &str,
+    cached_icons: &mut HashMap<String, i64>,
+    config: &Config,
+) -> Result<String, Box<dyn Error>> {
+    let val = match dict {
+        Value::Dict(val) => val,
+        _ => {
+            let icon = resolve_icon_name(app_icon, cached_icons, config);
+            if icon.is_empty() {
+                return Ok(last_resort_icon(None, appname, cached_icons, config));
+            }
+            return Ok(icon);
+        }
+    };
+
+    for key in ["image-data", "image_data"] {
+        if let Some(i) = val.get::<str, Structure>(key)? {
+            return cache_image_data(i, cached_icons, config);
+        }
+    }
+
+    for key in ["image-path", "image_path"] {
+        if let Some(p) = val.get::<str, str>(key)? {
+            // The image path may also be an icon name
+            match uri_to_path(p) {
+                Some(path) => match cache_image_file(&path, cached_icons, config) {
+                    Ok(cached) => return Ok(cached),
+                    Err(err) => eprintln!("Failed caching image {}: {}", path, err),
+                },
+                None => {
+                    let icon = theme_icon(p, cached_icons, config);
+                    if !icon.is_empty() {
+                        return Ok(icon);
+                    }
+                }
+            }
+        }
+    }
+
+    let icon = resolve_icon_name(app_icon, cached_icons, config);
+    if !icon.is_empty() {
+        return Ok(icon);
+    }
+
+    if let Some(i) = val.get::<str, Structure>("icon_data")? {
+        return cache_image_data(i, cached_icons, config);
+    }
+
+    let desktop_entry = val.get::<str, str>("desktop-entry").ok().flatten();
+    Ok(last_resort_icon(
+        desktop_entry,
+        appname,
+        cached_icons,
+        config,
+    ))
+}
+
+/// Icon for notifications that didn't bring a usable one: the icon of the application's .desktop
+/// file, or else the configured fallback icon
+pub fn last_resort_icon(
+    desktop_entry: Option<&str>,
+    appname: &str,
+    cached_icons: &mut HashMap<String, i64>,
+    config: &Config,
+) -> String {
+    if let Some(icon) = config.desktop_icons.find_icon(desktop_entry, appname) {
+        let icon = resolve_icon_name(&icon, cached_icons, config);
+        if !icon.is_empty() {
+            return icon;
+        }
+    }
+    match &config.fallback_icon {
+        Some(f) => resolve_icon_name(f, cached_icons, config),
+        None => String::new(),
+    }
+}
@@ -1,23 +1,115 @@
-use clap::Parser;
-use crypto::{digest::Digest, sha1::Sha1};
-use freedesktop_icons::lookup;
-use gdk_pixbuf::{glib::Bytes, Colorspace, Pixbuf};
+use clap::{Parser, Subcommand, ValueEnum};
+use config::Config;
+use control::Request;
+use icons::{check_format, last_resort_icon, release_icon, resolve_icon, theme_icon, IconFormat};
+use markup::MarkupMode;
+use output::{Event, OutputMode};
+use rules::apply_rules;
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
-    env,
     error::Error,
-    fs::{create_dir, read_dir, remove_file},
+    fs::{read_dir, read_to_string, remove_file},
     path::Path,
     sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, watch},
 };
 use zbus::{
     export::futures_util::TryStreamExt,
-    zvariant::{Structure, Value},
+    zvariant::{Dict, OwnedValue, Structure, Value},
     Connection, Message, MessageStream, MessageType,
 };
 use zbus_names::{InterfaceName, MemberName};
 
+mod config;
+mod control;
+mod desktop;
+mod dunstrc;
+mod icons;
+mod markup;
+mod output;
+mod rules;
+mod service;
+mod template;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct Action {
+    key: String,
+    label: String,
+}
+
+/// Standard hints from the freedesktop notification spec
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+struct Hints {
+    category: Option<String>,
+    desktop_entry: Option<String>,
+    transient: bool,
+    resident: bool,
+    sound_file: Option<String>,
+    sound_name: Option<String>,
+    suppress_sound: bool,
+    x: Option<i32>,
+    y: Option<i32>,
+    action_icons: bool,
+}
+
+impl Hints {
+    /// Read the hints from a Notify hints dictionary. Hints of the wrong type are ignored rather
+    /// than failing the whole notification.
+    fn from_dict(dict: &Dict) -> Hints {
+        let string = |key: &str| -> Option<String> {
+            dict.get::<str, str>(key).ok().flatten().map(String::from)
+        };
+        let boolean = |key: &str| -> bool { matches!(dict.get::<str, bool>(key), Ok(Some(true))) };
+        let int = |key: &str| -> Option<i32> { dict.get::<str, i32>(key).ok().flatten().copied() };
+        Hints {
+            category: string("category"),
+            desktop_entry: string("desktop-entry"),
+            // Like dunst, also take integers as sent by notify-send -h int:transient:1
+            transient: boolean("transient")
+                || int("transient").is_some_and(|t| t > 0)
+                || matches!(dict.get::<str, u32>("transient"), Ok(Some(t)) if *t > 0),
+            resident: boolean("resident"),
+            sound_file: string("sound-file"),
+            sound_name: string("sound-name"),
+            suppress_sound: boolean("suppress-sound"),
+            x: int("x"),
+            y: int("y"),
+            action_icons: boolean("action-icons"),
+        }
+    }
+}
+
+/// Reason given by the NotificationClosed signal
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum CloseReason {
+    /// The notification expired
+    Expired,
+    /// The notification was dismissed by the user
+    Dismissed,
+    /// The notification was closed by a call to CloseNotification
+    Closed,
+    /// Undefined or reserved reasons
+    Undefined,
+}
+
+impl From<u32> for CloseReason {
+    fn from(reason: u32) -> Self {
+        match reason {
+            1 => CloseReason::Expired,
+            2 => CloseReason::Dismissed,
+            3 => CloseReason::Closed,
+            _ => CloseReason::Undefined,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 struct Notification {
     serial: u32,
@@ -27,41 +119,531 @@ struct Notification {
     icon: String,
     urgency: u8,
     id: u32,
+    #[serde(default)]
+    actions: Vec<Action>,
+    /// Unique bus name of the application that sent the notification
+    #[serde(default)]
+    sender: String,
+    /// Time the notification was received, in seconds since the UNIX epoch
+    #[serde(default)]
+    timestamp: u64,
+    #[serde(default)]
+    timestamp_iso: String,
+    /// Time the notification was closed, in seconds since the UNIX epoch
+    #[serde(default)]
+    closed: Option<u64>,
+    #[serde(default)]
+    closed_iso: Option<String>,
+    /// Requested timeout in milliseconds. -1 leaves it up to dunst and 0 never expires
+    #[serde(default)]
+    expire_timeout: i32,
+    #[serde(default)]
+    close_reason: Option<CloseReason>,
+    #[serde(default)]
+    hints: Hints,
+    /// Set by rules to leave the notification out of the history
+    #[serde(skip)]
+    skip_history: bool,
+    /// Set by rules to move the notification to the history without displaying it
+    #[serde(skip)]
+    skip_display: bool,
+    /// Icon name or path the notification was sent with
+    #[serde(skip)]
+    app_icon: String,
+    #[serde(default)]
+    stack_tag: Option<String>,
+    /// Text of the notification rendered with the format of a matching rule
+    #[serde(default)]
+    formatted: Option<String>,
+    /// Targets of the links in the body
+    #[serde(default)]
+    links: Vec<String>,
+    /// Set through the control socket once the user has seen the notification
+    #[serde(default)]
+    read: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum CtlCommand {
+    /// Print the history, newest first
+    History,
+    /// Print a single notification from the history
+    Get { id: u32 },
+    /// Remove a notification from the history
+    Remove { id: u32 },
+    /// Clear the history
+    Clear,
+    /// Mark a notification in the history as read
+    Read { id: u32 },
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Invoke an action of a notification in the history through a running instance
+    InvokeAction {
+        /// ID of the notification
+        id: u32,
+        /// Key of the action to invoke
+        key: String,
+    },
+    /// Query or change the history of a running instance through its control socket
+    Ctl {
+        #[command(subcommand)]
+        command: CtlCommand,
+    },
 }
 
 #[derive(Parser, Debug)]
 #[command(version, about)]
 struct Args {
-    /// Length of the history. This should match dunst
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Configuration file to use instead of $XDG_CONFIG_HOME/disgustang/config.toml
+    #[arg(long)]
+    config: Option<String>,
+    /// Length of the history, 0 for unlimited. Defaults to the history_length from dunstrc
     #[arg(short, long)]
-    length: usize,
-    /// An additional icon theme to use for icon lookups
+    length: Option<usize>,
+    /// Icon theme to use for icon lookups. Can be repeated to fall back to other themes in order.
+    /// Defaults to the GTK icon theme
     #[arg(short, long)]
-    theme: Option<String>,
+    theme: Vec<String>,
+    /// Icon name or path to use when a notification has no icon
+    #[arg(long)]
+    fallback_icon: Option<String>,
     /// Location to save cached icons
     #[arg(short, long)]
     cache_dir: Option<String>,
+    /// File to persist the history to. Defaults to history.json inside the cache directory
+    #[arg(long)]
+    history_file: Option<String>,
+    /// Only keep notifications closed for these reasons in the history. Defaults to all reasons
+    #[arg(long, value_delimiter = ',')]
+    keep_reasons: Vec<CloseReason>,
+    /// Keep notifications with the transient hint in the history. Dunst leaves them out
+    #[arg(long)]
+    keep_transient: bool,
+    /// Copy icons given as file paths into the cache so they outlive the original file
+    #[arg(long)]
+    copy_icon_files: bool,
+    /// Size in pixels to look up theme icons at and to scale cached icons to
+    #[arg(long)]
+    icon_size: Option<u16>,
+    /// Scale factor of the icon size for HiDPI displays
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    icon_scale: Option<u16>,
+    /// Image format to save cached icons in. Defaults to png
+    #[arg(long, value_enum)]
+    icon_format: Option<IconFormat>,
+    /// PNG compression level (0-9) or JPEG/WebP quality (0-100) of cached icons
+    #[arg(long)]
+    icon_quality: Option<u8>,
+    /// Copy SVG icons into the cache as they are instead of converting them
+    #[arg(long)]
+    keep_svg: bool,
+    /// Copy icons found in the icon theme into the cache so the history doesn't depend on it
+    #[arg(long)]
+    cache_theme_icons: bool,
+    /// Don't apply the rules from dunstrc to the history
+    #[arg(long)]
+    ignore_dunst_rules: bool,
+    /// How to handle markup in notification bodies. Defaults to strip
+    #[arg(long, value_enum)]
+    markup: Option<MarkupMode>,
+    /// Path of the control socket. Defaults to $XDG_RUNTIME_DIR/disgustang.sock
+    #[arg(long)]
+    socket: Option<String>,
+    /// How to report changes to the history. Defaults to full
+    #[arg(long, value_enum)]
+    output: Option<OutputMode>,
+    /// Name of the eww variable to update in eww-update output mode. Defaults to notifications
+    #[arg(long)]
+    eww_variable: Option<String>,
+    /// Render each notification with this template instead of printing the history as JSON, e.g.
+    /// '(label :text {summary|yuck})'. Fields are id, appname, summary, body, icon, urgency,
+    /// timestamp and timestamp_iso
+    #[arg(long)]
+    format_template: Option<String>,
+    /// Report the notifications currently on screen along with the history as
+    /// {"active": [...], "history": [...]} in the full output format
+    #[arg(long)]
+    track_active: bool,
 }
 
-fn lookup_icon(theme: &str, name: &str) -> String {
-    match lookup(name)
-        .with_cache()
-        .with_theme("Adwaita")
-        .with_theme(theme)
-        .find()
-    {
-        Some(s) => s.into_os_string().into_string().unwrap_or(String::new()),
-        None => String::new(),
+/// How many seconds our timestamp of a notification may be off from the one dunst reports
+const SYNC_TOLERANCE: u64 = 2;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+/// Microseconds on the monotonic clock, which is what dunst timestamps notifications with
+fn monotonic_micros() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: ts is a valid timespec for clock_gettime to write to
+    if unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) } != 0 {
+        return 0;
+    }
+    ts.tv_sec as u64 * 1_000_000 + ts.tv_nsec as u64 / 1_000
+}
+
+/// Format seconds since the UNIX epoch as an ISO-8601 UTC timestamp
+fn iso8601(secs: u64) -> String {
+    // Civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = secs / 86400 + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    let rem = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+fn load_history(config: &Config) -> Result<Vec<Notification>, Box<dyn Error>> {
+    if !Path::new(&config.history_file).exists() {
+        return Ok(Vec::new());
+    }
+    let mut history: Vec<Notification> =
+        serde_json::from_str(&read_to_string(&config.history_file)?)?;
+    // Drop the oldest entries if the history length was lowered since the last run
+    history.drain(..config.history_excess(history.len()));
+    Ok(history)
+}
+
+fn save_history(history: &[Notification], path: &str) -> Result<(), Box<dyn Error>> {
+    let json = match serde_json::to_string(history) {
+        Ok(j) => j,
+        Err(_) => return Err("Failed history to string conversion".into()),
+    };
+    // Write to a temporary file first so a crash never leaves a truncated history behind
+    output::write_atomic(path, &json)
+}
+
+/// Save and report the history after it changed, and let the D-Bus service know about it
+fn history_changed(
+    buffer: &[Notification],
+    history: &[Notification],
+    events: &[Event],
+    config: &Config,
+    changes: &watch::Sender<()>,
+) {
+    if let Err(err) = save_history(history, &config.history_file) {
+        eprintln!("{}", err);
+    }
+    output::emit(buffer, history, events, config);
+    changes.send_replace(());
+}
+
+/// Report the notifications currently on screen after they changed, if they're being tracked
+fn active_changed(buffer: &[Notification], history: &[Notification], config: &Config) {
+    if config.track_active {
+        output::emit(buffer, history, &[], config);
     }
 }
 
+/// Seed the history with whatever dunst already has so it matches `dunstctl history`
+async fn sync_history(
+    connection: &Connection,
+    history: &mut Vec<Notification>,
+    cached_icons: &mut HashMap<String, i64>,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let reply = connection
+        .call_method(
+            Some("org.freedesktop.Notifications"),
+            "/org/freedesktop/Notifications",
+            Some("org.dunstproject.cmd0"),
+            "NotificationListHistory",
+            &(),
+        )
+        .await?;
+    let mut entries: Vec<HashMap<String, OwnedValue>> = reply.body()?;
+
+    let get_str = |entry: &HashMap<String, OwnedValue>, key: &str| -> String {
+        match entry.get(key) {
+            Some(v) => <&str>::try_from(v).unwrap_or("").to_string(),
+            None => String::new(),
+        }
+    };
+    let get_id = |entry: &HashMap<String, OwnedValue>| -> u32 {
+        match entry.get("id") {
+            Some(v) => i32::try_from(v).unwrap_or(0) as u32,
+            None => 0,
+        }
+    };
+
+    // Dunst IDs are handed out sequentially, so sorting by them gives the oldest first
+    entries.sort_by_key(get_id);
+
+    // Dunst reports timestamps from the monotonic clock in microseconds, which doesn't count time
+    // spent suspended, unlike the uptime
+    let boot = now_micros().saturating_sub(monotonic_micros());
+    entries.drain(..config.history_excess(entries.len()));
+
+    let timestamp = |entry: &HashMap<String, OwnedValue>| -> u64 {
+        match entry.get("timestamp") {
+            Some(v) => (boot + i64::try_from(v).unwrap_or(0).max(0) as u64) / 1_000_000,
+            None => 0,
+        }
+    };
+
+    // Anything we have that's newer than the oldest notification dunst still knows about was seen
+    // by the same dunst, so if dunst no longer has it, it was removed while we weren't watching
+    let session_start = entries.first().map(timestamp);
+
+    let mut synced: Vec<Notification> = Vec::with_capacity(entries.len());
+    for entry in entries.iter() {
+        let id = get_id(entry);
+        let appname = get_str(entry, "appname");
+        let summary = get_str(entry, "summary");
+        let timestamp = timestamp(entry);
+
+        // Prefer what we already know about this notification since it has the cached icon. IDs
+        // start over whenever dunst does, so the time it was received has to match as well.
+        if let Some(pos) = history.iter().rposition(|x| {
+            x.id == id
+                && x.appname == appname
+                && x.summary == summary
+                && x.timestamp.abs_diff(timestamp) <= SYNC_TOLERANCE
+        }) {
+            synced.push(history.remove(pos));
+            continue;
+        }
+
+        let icon_path = get_str(entry, "icon_path");
+        let mut links = Vec::new();
+        let icon = if Path::new(&icon_path).exists() {
+            icon_path.clone()
+        } else if !icon_path.is_empty() {
+            theme_icon(&icon_path, cached_icons, config)
+        } else {
+            last_resort_icon(None, &appname, cached_icons, config)
+        };
+        synced.push(Notification {
+            serial: 0,
+            appname,
+            summary,
+            body: markup::process(&get_str(entry, "body"), config.markup, &mut links),
+            icon,
+            urgency: match entry.get("urgency") {
+                Some(v) => match <&str>::try_from(v) {
+                    Ok("LOW") => 0,
+                    Ok("CRITICAL") => 2,
+                    Ok(_) => 1,
+                    Err(_) => u8::try_from(v).unwrap_or(1),
+                },
+                None => 1,
+            },
+            id,
+            actions: Vec::new(),
+            sender: String::new(),
+            timestamp,
+            timestamp_iso: iso8601(timestamp),
+            closed: None,
+            closed_iso: None,
+            close_reason: None,
+            hints: Hints {
+                category: Some(get_str(entry, "category")).filter(|x| !x.is_empty()),
+                ..Hints::default()
+            },
+            skip_history: false,
+            skip_display: false,
+            app_icon: icon_path,
+            stack_tag: Some(get_str(entry, "stack_tag")).filter(|x| !x.is_empty()),
+            formatted: None,
+            links,
+            read: false,
+            expire_timeout: match entry.get("timeout") {
+                Some(v) => (i64::try_from(v).unwrap_or(-1000) / 1000) as i32,
+                None => -1,
+            },
+        });
+    }
+
+    if let Some(start) = session_start {
+        history.retain(|n| {
+            let kept = n.timestamp + SYNC_TOLERANCE < start;
+            if !kept {
+                release_icon(cached_icons, &n.icon);
+            }
+            kept
+        });
+    }
+    // Whatever we kept is older than what dunst has, e.g. from before logging out
+    let excess = config.history_excess(history.len() + synced.len());
+    for n in history.drain(..excess) {
+        release_icon(cached_icons, &n.icon);
+    }
+    history.extend(synced);
+    Ok(())
+}
+
+/// Remove a notification from the history. Returns false if there was no such notification.
+fn remove_from_history(
+    id: u32,
+    buffer: &[Notification],
+    history: &mut Vec<Notification>,
+    cached_icons: &mut HashMap<String, i64>,
+    config: &Config,
+    changes: &watch::Sender<()>,
+) -> bool {
+    // IDs start over when dunst is restarted, so an older notification from before that may share
+    // the ID. The newest one is always the one meant.
+    let removed = match history.iter().rposition(|x| x.id == id) {
+        Some(pos) => history.remove(pos),
+        None => return false,
+    };
+    release_icon(cached_icons, &removed.icon);
+
+    history_changed(
+        buffer,
+        history,
+        &[Event::Removed(&removed)],
+        config,
+        changes,
+    );
+    true
+}
+
+/// Remove everything from the history, leaving the notifications on screen alone
+fn clear_history(
+    buffer: &[Notification],
+    history: &mut Vec<Notification>,
+    cached_icons: &mut HashMap<String, i64>,
+    config: &Config,
+    changes: &watch::Sender<()>,
+) {
+    for n in history.drain(..) {
+        release_icon(cached_icons, &n.icon);
+    }
+    history_changed(buffer, history, &[Event::Cleared], config, changes);
+}
+
+/// Answer a request from the control socket
+fn handle_request(
+    request: Request,
+    buffer: &[Notification],
+    history: &mut Vec<Notification>,
+    cached_icons: &mut HashMap<String, i64>,
+    config: &Config,
+    changes: &watch::Sender<()>,
+) -> Result<String, String> {
+    match request {
+        Request::History => output::history_json(history).map_err(|err| err.to_string()),
+        Request::Get(id) => match history.iter().rev().find(|x| x.id == id) {
+            Some(n) => serde_json::to_string(n).map_err(|err| err.to_string()),
+            None => Err(format!("No notification with ID {} in the history", id)),
+        },
+        Request::Remove(id) => {
+            if remove_from_history(id, buffer, history, cached_icons, config, changes) {
+                Ok(String::from("true"))
+            } else {
+                Err(format!("No notification with ID {} in the history", id))
+            }
+        }
+        Request::Clear => {
+            clear_history(buffer, history, cached_icons, config, changes);
+            Ok(String::from("true"))
+        }
+        Request::MarkRead(id) => match history.iter().rposition(|x| x.id == id) {
+            Some(pos) => {
+                history[pos].read = true;
+                history_changed(
+                    buffer,
+                    history,
+                    &[Event::Updated(&history[pos])],
+                    config,
+                    changes,
+                );
+                Ok(String::from("true"))
+            }
+            None => Err(format!("No notification with ID {} in the history", id)),
+        },
+        // The main loop emits the signal to the sender this answers with
+        Request::InvokeAction(id, key) => match history.iter().rev().find(|x| x.id == id) {
+            Some(n) if !n.actions.iter().any(|x| x.key == key) => {
+                Err(format!("Notification {} has no action {}", id, key))
+            }
+            Some(n) if n.sender.is_empty() => Err(format!(
+                "It's unknown which application sent notification {}",
+                id
+            )),
+            Some(n) => Ok(n.sender.clone()),
+            None => Err(format!("No notification with ID {} in the history", id)),
+        },
+    }
+}
+
+/// Whether a notification belongs in the history at all
+fn keeps_history(notification: &Notification, config: &Config) -> bool {
+    !((notification.hints.transient && !config.keep_transient) || notification.skip_history)
+}
+
+/// Move a notification that's no longer displayed into the history. Returns false if it was left
+/// out of the history.
+fn push_history(
+    mut notification: Notification,
+    buffer: &[Notification],
+    history: &mut Vec<Notification>,
+    cached_icons: &mut HashMap<String, i64>,
+    config: &Config,
+    changes: &watch::Sender<()>,
+) -> bool {
+    if !keeps_history(&notification, config) {
+        release_icon(cached_icons, &notification.icon);
+        return false;
+    }
+
+    let evicted = if config.history_excess(history.len() + 1) > 0 {
+        let oldest = history.remove(0);
+        release_icon(cached_icons, &oldest.icon);
+        Some(oldest)
+    } else {
+        None
+    };
+    let closed_at = now();
+    notification.closed = Some(closed_at);
+    notification.closed_iso = Some(iso8601(closed_at));
+    history.push(notification);
+
+    let mut events: Vec<Event> = evicted.iter().map(Event::Removed).collect();
+    events.push(Event::Added(&history[history.len() - 1]));
+    history_changed(buffer, history, &events, config, changes);
+    true
+}
+
 fn handle_msg(
     msg: &mut Message,
     buffer: &mut Vec<Notification>,
     history: &mut Vec<Notification>,
     cached_icons: &mut HashMap<String, i64>,
-    theme: &str,
-    cache_dir: &str,
+    config: &Config,
+    changes: &watch::Sender<()>,
 ) -> Result<(), Box<dyn Error>> {
     let body = msg.body::<Structure>();
 
@@ -73,71 +655,34 @@ fn handle_msg(
                     let body = body.unwrap();
                     let fields = body.fields();
                     let dict: Value = fields[6].clone();
-                    buffer.push(Notification {
+                    let replaces_id = u32::try_from(fields[1].clone()).unwrap_or(0);
+                    let mut links = Vec::new();
+                    let mut notification = Notification {
                         serial: if let Some(s) = msg.primary_header().serial_num() {
                             *s
                         } else {
                             return Err("Failed getting serial number from message".into());
                         },
-                        appname: String::try_from(fields[0].clone()).unwrap_or(String::new())
-                            .replace("&amp;", "&")
-                            .replace("&lt;", "<")
-                            .replace("&gt;", ">"),
-                        summary: String::try_from(fields[3].clone()).unwrap_or(String::new())
-                            .replace("&amp;", "&")
-                            .replace("&lt;", "<")
-                            .replace("&gt;", ">"),
-                        body: String::try_from(fields[4].clone()).unwrap_or(String::new())
-                            .replace("&amp;", "&")
-                            .replace("&lt;", "<")
-                            .replace("&gt;", ">"),
-                        icon: if let Value::Dict(val) = &dict {
-                            match val.get::<str, Structure>("image-data")? {
-                                Some(i) => {
-                                    let img_fields = i.fields();
-                                    let bytes = if let Value::Array(a) = img_fields[6].clone() {
-                                        Bytes::from(
-                                            &(a.get()
-                                                .iter()
-                                                .map(|x| u8::try_from(x).unwrap())
-                                                .collect::<Vec<u8>>()),
-                                        )
-                                    } else {
-                                        Bytes::from_static(&[])
-                                    };
-                                    let mut hasher = Sha1::new();
-                                    hasher.input(&bytes);
-                                    let path = format!("{}/{}.png", cache_dir, hasher.result_str());
-                                    if !Path::new(&path).exists() {
-                                        Pixbuf::from_bytes(
-                                            &bytes,
-                                            Colorspace::Rgb,
-                                            bool::try_from(img_fields[3].clone())?,
-                                            i32::try_from(img_fields[4].clone())?,
-                                            i32::try_from(img_fields[0].clone())?,
-                                            i32::try_from(img_fields[1].clone())?,
-                                            i32::try_from(img_fields[2].clone())?,
-                                        )
-                                        .savev(
-                                            &path,
-                                            "png",
-                                            &[],
-                                        )?;
-                                    }
-                                    *cached_icons.entry(path.clone()).or_insert(0) += 1;
-                                    path
-                                }
-                                None => lookup_icon(
-                                    theme,
-                                    &String::try_from(fields[2].clone()).unwrap_or(String::new()),
-                                ),
-                            }
-                        } else {
-                            lookup_icon(
-                                theme,
-                                &String::try_from(fields[2].clone()).unwrap_or(String::new()),
-                            )
-                        },
+                        appname: markup::plain(
+                            &String::try_from(fields[0].clone()).unwrap_or(String::new()),
+                            config.markup,
+                        ),
+                        summary: markup::plain(
+                            &String::try_from(fields[3].clone()).unwrap_or(String::new()),
+                            config.markup,
+                        ),
+                        body: markup::process(
+                            &String::try_from(fields[4].clone()).unwrap_or(String::new()),
+                            config.markup,
+                            &mut links,
+                        ),
+                        icon: resolve_icon(
+                            &dict,
+                            &String::try_from(fields[2].clone()).unwrap_or(String::new()),
+                            &String::try_from(fields[0].clone()).unwrap_or(String::new()),
+                            cached_icons,
+                            config,
+                        )?,
                         urgency: if let Value::Dict(val) = &dict {
                             match val.get("urgency")? {
                                 Some(i) => *i,
@@ -147,45 +692,80 @@ fn handle_msg(
                             1
                         },
                         id: 0,
-                    });
+                        actions: if let Value::Array(a) = &fields[5] {
+                            // Actions are sent as a flat list of alternating keys and labels
+                            a.get()
+                                .chunks_exact(2)
+                                .map(|c| Action {
+                                    key: String::try_from(c[0].clone()).unwrap_or(String::new()),
+                                    label: String::try_from(c[1].clone()).unwrap_or(String::new()),
+                                })
+                                .collect()
+                        } else {
+                            Vec::new()
+                        },
+                        sender: match msg.header()?.sender()? {
+                            Some(s) => s.to_string(),
+                            None => String::new(),
+                        },
+                        timestamp: now(),
+                        timestamp_iso: String::new(),
+                        closed: None,
+                        closed_iso: None,
+                        expire_timeout: i32::try_from(fields[7].clone()).unwrap_or(-1),
+                        close_reason: None,
+                        hints: if let Value::Dict(val) = &dict {
+                            Hints::from_dict(val)
+                        } else {
+                            Hints::default()
+                        },
+                        skip_history: false,
+                        skip_display: false,
+                        app_icon: String::try_from(fields[2].clone()).unwrap_or(String::new()),
+                        stack_tag: None,
+                        formatted: None,
+                        links,
+                        read: false,
+                    };
+                    notification.timestamp_iso = iso8601(notification.timestamp);
+
+                    if !apply_rules(&mut notification, cached_icons, config) {
+                        return Ok(());
+                    }
+
+                    // Updates to an existing notification replace it instead of adding a new one
+                    if replaces_id != 0 {
+                        notification.id = replaces_id;
+                        if let Some(s) = buffer.iter_mut().find(|x| x.id == replaces_id) {
+                            let old = std::mem::replace(s, notification);
+                            release_icon(cached_icons, &old.icon);
+                            return Ok(());
+                        }
+                        // Dunst shows the update again, so it's no longer part of the history
+                        // until it's closed
+                        if let Some(pos) = history.iter().rposition(|x| x.id == replaces_id) {
+                            let old = history.remove(pos);
+                            release_icon(cached_icons, &old.icon);
+                            history_changed(
+                                buffer,
+                                history,
+                                &[Event::Removed(&old)],
+                                config,
+                                changes,
+                            );
+                        }
+                    }
+                    buffer.push(notification);
                 } else if iface == InterfaceName::try_from("org.dunstproject.cmd0")? {
                     if let Some(member) = msg.member() {
                         if member == MemberName::try_from("NotificationRemoveFromHistory")? {
                             let body = body.unwrap();
                             let fields = body.fields();
                             let id = u32::try_from(fields[0].clone())?;
-                            let icon_path =
-                                history.iter().find(|x| x.id == id).unwrap().icon.clone();
-                            history.remove(history.iter().position(|x| x.id == id).unwrap());
-
-                            match cached_icons.get_mut(&icon_path) {
-                                None => (),
-                                Some(e) => {
-                                    *e -= 1;
-                                    if *e <= 0 {
-                                        if remove_file(&icon_path).is_err() {
-                                            eprintln!("Failed removing file {}", &icon_path);
-                                        }
-                                        cached_icons.remove(&icon_path);
-                                    }
-                                }
-                            }
-
-                            if let Err(err) = print_json(history) {
-                                eprintln!("{}", err);
-                            }
+                            remove_from_history(id, buffer, history, cached_icons, config, changes);
                         } else if member == MemberName::try_from("NotificationClearHistory")? {
-                            buffer.drain(..);
-                            history.drain(..);
-
-                            for (icon, _) in cached_icons.iter_mut() {
-                                if remove_file(icon).is_err() {
-                                    eprintln!("Failed removing file {}", &icon);
-                                }
-                            }
-
-                            cached_icons.drain();
-                            println!("[]");
+                            // Dunst keeps showing what's on screen, so only the history goes
+                            clear_history(buffer, history, cached_icons, config, changes);
                         }
                     }
                 }
@@ -201,10 +781,29 @@ fn handle_msg(
             } else {
                 return Ok(());
             };
-            let fields = body.fields();
-            match buffer.iter_mut().find(|x| x.serial == reply_serial) {
-                Some(s) => s.id = u32::try_from(fields[0].clone()).unwrap(),
+            // Serials are only unique per connection, so the reply has to go to the sender too
+            let destination = match msg.header()?.destination()? {
+                Some(d) => d.to_string(),
+                None => return Ok(()),
+            };
+            let pos = match buffer
+                .iter()
+                .position(|x| x.serial == reply_serial && x.sender == destination)
+            {
+                Some(pos) => pos,
                 None => return Ok(()),
+            };
+            buffer[pos].id = match body.fields().first().map(|f| u32::try_from(f.clone())) {
+                Some(Ok(id)) => id,
+                _ => return Ok(()),
+            };
+
+            // Dunst sends these straight to its history without ever displaying them
+            if buffer[pos].skip_display {
+                let notification = buffer.remove(pos);
+                push_history(notification, buffer, history, cached_icons, config, changes);
+            } else {
+                active_changed(buffer, history, config);
             }
         }
         MessageType::Signal => {
@@ -212,22 +811,21 @@ fn handle_msg(
                 if member == MemberName::try_from("NotificationClosed")? {
                     let body = body.unwrap();
                     let fields = body.fields();
-                    match buffer
-                        .iter()
-                        .find(|x| x.id == u32::try_from(fields[0].clone()).unwrap())
-                    {
-                        Some(s) => {
-                            if history.len() == history.capacity() {
-                                history.remove(0);
-                            }
-                            history.push(s.clone());
-
-                            buffer.remove(buffer.iter().position(|x| x.id == s.id).unwrap());
-                            if let Err(err) = print_json(history) {
-                                eprintln!("{}", err);
-                            }
-                        }
+                    let id = u32::try_from(fields[0].clone())?;
+                    let reason = CloseReason::from(u32::try_from(fields[1].clone()).unwrap_or(4));
+                    let mut closed = match buffer.iter().position(|x| x.id == id) {
+                        Some(pos) => buffer.remove(pos),
                         None => return Ok(()),
+                    };
+
+                    if !config.keep_reasons.is_empty() && !config.keep_reasons.contains(&reason) {
+                        release_icon(cached_icons, &closed.icon);
+                        active_changed(buffer, history, config);
+                        return Ok(());
+                    }
+                    closed.close_reason = Some(reason);
+                    if !push_history(closed, buffer, history, cached_icons, config, changes) {
+                        active_changed(buffer, history, config);
                     }
                 }
             }
@@ -237,39 +835,128 @@ fn handle_msg(
     Ok(())
 }
 
-fn print_json(history: &Vec<Notification>) -> Result<(), Box<dyn Error>> {
-    let mut hist = history.clone();
-    hist.reverse();
-    let json = match serde_json::to_string(&hist) {
-        Ok(j) => j,
-        Err(_) => return Err("Failed history to string conversion".into()),
-    };
-    println!("{}", json);
-    Ok(())
+/// Read the configuration again, keeping the current history, buffer and cached icons
+fn reload_config(
+    args: &Args,
+    current: &Config,
+    buffer: &[Notification],
+    history: &mut Vec<Notification>,
+    cached_icons: &mut HashMap<String, i64>,
+    changes: &watch::Sender<()>,
+) -> Result<Config, Box<dyn Error>> {
+    let mut config = Config::load(args)?;
+    check_format(config.icon_format)?;
+
+    // Moving the cache, history or socket around while running isn't supported
+    if config.cache_dir != current.cache_dir
+        || config.history_file != current.history_file
+        || config.socket != current.socket
+    {
+        eprintln!("The cache directory, history file and socket can only be changed by restarting");
+        config.cache_dir = current.cache_dir.clone();
+        config.history_file = current.history_file.clone();
+        config.socket = current.socket.clone();
+    }
+
+    let excess = config.history_excess(history.len());
+    if excess > 0 {
+        let evicted: Vec<Notification> = history.drain(..excess).collect();
+        for n in evicted.iter() {
+            release_icon(cached_icons, &n.icon);
+        }
+
+        let events: Vec<Event> = evicted.iter().map(Event::Removed).collect();
+        history_changed(buffer, history, &events, &config, changes);
+    }
+    Ok(config)
+}
+
+/// Emit ActionInvoked to the application that sent a notification in the history. This is a best
+/// effort since the signal doesn't come from the notification daemon, which has long forgotten
+/// about the notification by then, so applications checking where it came from will ignore it.
+async fn invoke_action(sender: &str, id: u32, key: &str) -> zbus::Result<()> {
+    let connection = Connection::session().await?;
+    let dbus = zbus::fdo::DBusProxy::new(&connection).await?;
+    if !dbus.name_has_owner(sender.try_into()?).await? {
+        return Err(zbus::Error::Failure(format!(
+            "The application that sent notification {} is no longer running",
+            id
+        )));
+    }
+    connection
+        .emit_signal(
+            Some(sender),
+            "/org/freedesktop/Notifications",
+            "org.freedesktop.Notifications",
+            "ActionInvoked",
+            &(id, key),
+        )
+        .await
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
+
+    // Clients only need to find the running instance
+    if let Some(command) = &args.command {
+        let socket = config::socket(&args)?;
+        return match command {
+            Command::InvokeAction { id, key } => {
+                control::send(&socket, &format!("invoke {} {}", id, key)).await?;
+                eprintln!(
+                    "Sent ActionInvoked to the application of notification {}. It doesn't come from the notification daemon, so the application may ignore it and it can't be confirmed that it was accepted",
+                    id
+                );
+                Ok(())
+            }
+            Command::Ctl { command } => {
+                let line = match command {
+                    CtlCommand::History => String::from("history"),
+                    CtlCommand::Get { id } => format!("get {}", id),
+                    CtlCommand::Remove { id } => format!("remove {}", id),
+                    CtlCommand::Clear => String::from("clear"),
+                    CtlCommand::Read { id } => format!("read {}", id),
+                };
+                println!("{}", control::send(&socket, &line).await?);
+                Ok(())
+            }
+        };
+    }
+
+    let mut config = Config::load(&args)?;
     let mut buffer: Vec<Notification> = Vec::new();
-    let mut history: Vec<Notification> = Vec::with_capacity(args.length);
     let mut cached_icons: HashMap<String, i64> = HashMap::new();
-    let theme = args.theme.unwrap_or(String::from("Adwaita"));
-    let cache_dir = args.cache_dir.unwrap_or_else(|| {
-        let path = format!(
-            "{}/{}",
-            env::var("XDG_CACHE_HOME").unwrap_or(String::from(".")),
-            "disgustang"
-        );
-        if !Path::new(&path).exists() && create_dir(&path).is_err() {
-            panic!("Failed creating cache directory {}", path);
+    check_format(config.icon_format)?;
+    let mut history = match load_history(&config) {
+        Ok(h) => h,
+        Err(err) => {
+            eprintln!(
+                "Failed loading history from {}: {}",
+                config.history_file, err
+            );
+            Vec::new()
+        }
+    };
+
+    // Rebuild the icon refcounts from the saved history
+    for n in history.iter() {
+        if Path::new(&n.icon).parent() == Some(Path::new(&config.cache_dir))
+            && Path::new(&n.icon).exists()
+        {
+            *cached_icons.entry(n.icon.clone()).or_insert(0) += 1;
         }
-        path
-    });
+    }
 
-    let paths = read_dir(&cache_dir).unwrap();
-    for p in paths {
-        let p = p.unwrap().path();
+    // Remove any cached icons no longer referenced by the history. Compare paths rather than
+    // strings since the cache directory may have been given with a trailing slash or ./
+    for p in read_dir(&config.cache_dir)? {
+        let p = p?.path();
+        if p == Path::new(&config.history_file)
+            || cached_icons.keys().any(|icon| Path::new(icon) == p)
+        {
+            continue;
+        }
         if remove_file(&p).is_err() {
             eprintln!("Failed removing file {}", p.display());
         }
@@ -283,6 +970,21 @@ async fn main() -> Result<(), Box<dyn Error>> {
         "type='method_call',interface='org.dunstproject.cmd0',member='NotificationClearHistory'",
     ];
     let connection = Connection::session().await?;
+
+    // This has to happen before becoming a monitor since monitors can't make method calls
+    match sync_history(&connection, &mut history, &mut cached_icons, &config).await {
+        Ok(()) => {
+            if let Err(err) = save_history(&history, &config.history_file) {
+                eprintln!("{}", err);
+            }
+        }
+        Err(err) => eprintln!("Failed syncing history with dunst: {}", err),
+    }
+    if !history.is_empty() {
+        let events: Vec<Event> = history.iter().map(Event::Added).collect();
+        output::emit(&buffer, &history, &events, &config);
+    }
+
     connection
         .call_method(
             Some("org.freedesktop.DBus"),
@@ -293,20 +995,95 @@ async fn main() -> Result<(), Box<dyn Error>> {
         )
         .await?;
 
+    let (requests_tx, mut requests) = mpsc::channel(16);
+    let (changes, changes_rx) = watch::channel(());
+    let _service = match service::serve(requests_tx.clone(), changes_rx).await {
+        Ok(c) => Some(c),
+        Err(err) => {
+            eprintln!(
+                "Failed registering {} on the session bus: {}",
+                service::NAME,
+                err
+            );
+            None
+        }
+    };
+    if let Err(err) = control::listen(&config.socket, requests_tx) {
+        eprintln!("Failed listening on {}: {}", config.socket, err);
+    }
+
+    let mut hangup = signal(SignalKind::hangup())?;
     let mut stream = MessageStream::from(connection);
-    // Is this really the only way to get the inner value of the Arcs from this stream?
-    while let Some(mut msg) = stream.try_next().await? {
-        if let Err(err) = handle_msg(
-            Arc::<zbus::Message>::make_mut(&mut msg),
-            &mut buffer,
-            &mut history,
-            &mut cached_icons,
-            &theme,
-            &cache_dir,
-        ) {
-            eprintln!("{}", err);
+    loop {
+        tokio::select! {
+            msg = stream.try_next() => {
+                let mut msg = match msg? {
+                    Some(m) => m,
+                    None => break,
+                };
+                // Is this really the only way to get the inner value of the Arcs from this stream?
+                if let Err(err) = handle_msg(
+                    Arc::<zbus::Message>::make_mut(&mut msg),
+                    &mut buffer,
+                    &mut history,
+                    &mut cached_icons,
+                    &config,
+                    &changes,
+                ) {
+                    eprintln!("{}", err);
+                }
+            }
+            Some((request, reply)) = requests.recv() => {
+                let invoke = match &request {
+                    Request::InvokeAction(id, key) => Some((*id, key.clone())),
+                    _ => None,
+                };
+                let result = handle_request(
+                    request,
+                    &buffer,
+                    &mut history,
+                    &mut cached_icons,
+                    &config,
+                    &changes,
+                );
+                match (invoke, result) {
+                    // Emitting the signal needs a connection of its own, so don't hold up the loop
+                    (Some((id, key)), Ok(sender)) => {
+                        tokio::spawn(async move {
+                            let result = invoke_action(&sender, id, &key)
+                                .await
+                                .map(|()| String::from("true"))
+                                .map_err(|err| err.to_string());
+                            let _ = reply.send(result);
+                        });
+                    }
+                    (_, result) => {
+                        let _ = reply.send(result);
+                    }
+                }
+            }
+            _ = hangup.recv() => {
+                match reload_config(&args, &config, &buffer, &mut history, &mut cached_icons, &changes) {
+                    Ok(c) => config = c,
+                    Err(err) => eprintln!("Failed reloading configuration: {}", err),
+                }
+            }
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_iso8601() {
+        assert_eq!(iso8601(0), "1970-01-01T00:00:00Z");
+        assert_eq!(iso8601(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(iso8601(1_700_000_000), "2023-11-14T22:13:20Z");
+        assert_eq!(iso8601(1_709_251_199), "2024-02-29T23:59:59Z");
+        assert_eq!(iso8601(4_102_444_800), "2100-01-01T00:00:00Z");
+    }
+}
@@ -0,0 +1,343 @@
+use clap::ValueEnum;
+use regex::Regex;
+use serde::Deserialize;
+use std::sync::LazyLock;
+
+/// How to handle markup in notification bodies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum MarkupMode {
+    /// Remove all markup and leave plain text
+    Strip,
+    /// Keep only the markup GTK labels understand
+    Pango,
+    /// Leave the body as it was sent
+    Raw,
+}
+
+/// Tags understood by Pango and thus GTK labels
+const PANGO_TAGS: [&str; 11] = [
+    "a", "b", "big", "i", "s", "small", "span", "sub", "sup", "tt", "u",
+];
+
+/// Decode a single entity without the surrounding & and ;
+fn decode_entity(entity: &str) -> Option<char> {
+    match entity {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some('\u{a0}'),
+        _ => {
+            let code = match entity.strip_prefix('#') {
+                Some(n) => match n.strip_prefix(['x', 'X']) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                    None => n.parse().ok()?,
+                },
+                None => return None,
+            };
+            char::from_u32(code)
+        }
+    }
+}
+
+fn escape(c: char, out: &mut String) {
+    match c {
+        '&' => out.push_str("&amp;"),
+        '<' => out.push_str("&lt;"),
+        '>' => out.push_str("&gt;"),
+        _ => out.push(c),
+    }
+}
+
+/// Write an attribute value in double quotes, escaped so that a stray & or quote in it can't
+/// break the markup
+fn quote_attribute(value: &str, out: &mut String) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("&quot;"),
+            _ => escape(c, out),
+        }
+    }
+    out.push('"');
+}
+
+/// Replace all entities with the characters they stand for
+pub fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(pos) = rest.find('&') {
+        decoded.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        match rest[1..]
+            .find(';')
+            .and_then(|end| decode_entity(&rest[1..end + 1]).map(|c| (c, end + 2)))
+        {
+            Some((c, len)) => {
+                decoded.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Process text that isn't supposed to contain markup, like the summary
+pub fn plain(text: &str, mode: MarkupMode) -> String {
+    match mode {
+        MarkupMode::Raw => text.to_string(),
+        _ => decode_entities(text),
+    }
+}
+
+static HREF: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?i)\bhref\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap());
+
+static ATTRIBUTE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"([\w-]+)\s*=\s*("[^"]*"|'[^']*')"#).unwrap());
+
+/// Pull the link target out of the inside of an <a> tag
+fn href(tag: &str) -> Option<String> {
+    let captures = HREF.captures(tag)?;
+    captures
+        .get(1)
+        .or(captures.get(2))
+        .map(|m| decode_entities(m.as_str()))
+}
+
+/// Attributes of <span> that Pango knows about
+const SPAN_ATTRIBUTES: [&str; 22] = [
+    "font",
+    "font_desc",
+    "font_family",
+    "face",
+    "size",
+    "style",
+    "weight",
+    "variant",
+    "stretch",
+    "foreground",
+    "fgcolor",
+    "color",
+    "background",
+    "bgcolor",
+    "alpha",
+    "fgalpha",
+    "bgalpha",
+    "underline",
+    "underline_color",
+    "strikethrough",
+    "rise",
+    "letter_spacing",
+];
+
+/// Rebuild a <span> tag with only the attributes Pango accepts, since anything else makes the
+/// whole text fail to parse
+fn span_tag(inner: &str) -> String {
+    let mut tag = String::from("<span");
+    for captures in ATTRIBUTE.captures_iter(inner) {
+        if SPAN_ATTRIBUTES.contains(&captures[1].to_ascii_lowercase().as_str()) {
+            let quoted = &captures[2];
+            tag.push_str(&format!(" {}=", &captures[1]));
+            quote_attribute(&decode_entities(&quoted[1..quoted.len() - 1]), &mut tag);
+        }
+    }
+    tag.push('>');
+    tag
+}
+
+/// Process the markup of a notification body, collecting the targets of any links
+pub fn process(text: &str, mode: MarkupMode, links: &mut Vec<String>) -> String {
+    let mut out = String::with_capacity(text.len());
+    // Tags left open in pango mode, so that the result is always well formed
+    let mut open: Vec<String> = Vec::new();
+    let mut rest = text;
+
+    while let Some(c) = rest.chars().next() {
+        if c == '<' {
+            if let Some(end) = rest.find('>') {
+                let inner = &rest[1..end];
+                let closing = inner.starts_with('/');
+                let name: String = inner
+                    .trim_start_matches('/')
+                    .chars()
+                    .take_while(|c| c.is_ascii_alphanumeric())
+                    .collect::<String>()
+                    .to_ascii_lowercase();
+                if !name.is_empty() {
+                    rest = &rest[end + 1..];
+                    if name == "a" && !closing {
+                        if let Some(href) = href(inner) {
+                            links.push(href);
+                        }
+                    }
+                    match mode {
+                        MarkupMode::Raw => out.push_str(&format!("<{}>", inner)),
+                        MarkupMode::Strip => {
+                            if name == "br" {
+                                out.push('\n');
+                            }
+                        }
+                        MarkupMode::Pango => {
+                            if name == "br" {
+                                out.push('\n');
+                            } else if !PANGO_TAGS.contains(&name.as_str()) {
+                                // Unsupported tags are dropped but their contents kept
+                            } else if closing {
+                                if open.last() == Some(&name) {
+                                    open.pop();
+                                    out.push_str(&format!("</{}>", name));
+                                }
+                            } else if inner.ends_with('/') {
+                                // Self-closing formatting tags don't do anything
+                            } else if name == "a" && href(inner).is_none() {
+                                // Pango rejects links without a target, so only the text is kept
+                            } else {
+                                match name.as_str() {
+                                    "span" => out.push_str(&span_tag(inner)),
+                                    "a" => {
+                                        out.push_str("<a href=");
+                                        quote_attribute(&href(inner).unwrap_or_default(), &mut out);
+                                        out.push('>');
+                                    }
+                                    _ => out.push_str(&format!("<{}>", name)),
+                                }
+                                open.push(name);
+                            }
+                        }
+                    }
+                    continue;
+                }
+            }
+        } else if c == '&' && mode != MarkupMode::Raw {
+            let decoded = rest[1..]
+                .find(';')
+                .filter(|end| *end <= 10)
+                .and_then(|end| decode_entity(&rest[1..end + 1]).map(|c| (c, end + 2)));
+            if let Some((c, len)) = decoded {
+                match mode {
+                    MarkupMode::Pango => escape(c, &mut out),
+                    _ => out.push(c),
+                }
+                rest = &rest[len..];
+                continue;
+            }
+        }
+
+        // Plain text, which needs escaping if it's going to be parsed as markup again
+        match mode {
+            MarkupMode::Pango => escape(c, &mut out),
+            _ => out.push(c),
+        }
+        rest = &rest[c.len_utf8()..];
+    }
+
+    while let Some(name) = open.pop() {
+        out.push_str(&format!("</{}>", name));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process_links(text: &str, mode: MarkupMode) -> (String, Vec<String>) {
+        let mut links = Vec::new();
+        let out = process(text, mode, &mut links);
+        (out, links)
+    }
+
+    #[test]
+    fn decodes_entities() {
+        assert_eq!(decode_entities("a &amp; b &lt;c&gt;"), "a & b <c>");
+        assert_eq!(decode_entities("&#65;&#x42;&#X43;&nbsp;"), "ABC\u{a0}");
+        assert_eq!(decode_entities("&bogus; & &amp"), "&bogus; & &amp");
+        assert_eq!(decode_entities("&#xD800;"), "&#xD800;");
+    }
+
+    #[test]
+    fn plain_leaves_raw_text_alone() {
+        assert_eq!(plain("&lt;b&gt;", MarkupMode::Strip), "<b>");
+        assert_eq!(plain("&lt;b&gt;", MarkupMode::Raw), "&lt;b&gt;");
+    }
+
+    #[test]
+    fn strips_markup() {
+        let (out, _) = process_links("<b>bold</b> <foo>x</foo><br/>a &amp; b", MarkupMode::Strip);
+        assert_eq!(out, "bold x\na & b");
+    }
+
+    #[test]
+    fn keeps_text_that_only_looks_like_tags() {
+        let (out, _) = process_links("1 < 2 and 3 <> 4 <", MarkupMode::Strip);
+        assert_eq!(out, "1 < 2 and 3 <> 4 <");
+        let (out, _) = process_links("1 < 2", MarkupMode::Pango);
+        assert_eq!(out, "1 &lt; 2");
+    }
+
+    #[test]
+    fn pango_escapes_text_and_keeps_known_tags() {
+        let (out, _) = process_links(
+            "<B>a</b> & <i>&lt;b&gt;</i> <foo>c</foo>",
+            MarkupMode::Pango,
+        );
+        assert_eq!(out, "<b>a</b> &amp; <i>&lt;b&gt;</i> c");
+    }
+
+    #[test]
+    fn pango_balances_tags() {
+        let (out, _) = process_links("<b><i>unclosed", MarkupMode::Pango);
+        assert_eq!(out, "<b><i>unclosed</i></b>");
+        let (out, _) = process_links("stray</b><b>x</i></b>", MarkupMode::Pango);
+        assert_eq!(out, "stray<b>x</b>");
+        let (out, _) = process_links("<b/>x", MarkupMode::Pango);
+        assert_eq!(out, "x");
+    }
+
+    #[test]
+    fn pango_filters_span_attributes() {
+        let (out, _) = process_links(
+            "<span foreground='red' onclick=\"x\" size=\"large\">t</span>",
+            MarkupMode::Pango,
+        );
+        assert_eq!(out, "<span foreground=\"red\" size=\"large\">t</span>");
+        let (out, _) = process_links(
+            "<span font='A & B' face=\"&quot;C&amp;D\">t</span>",
+            MarkupMode::Pango,
+        );
+        assert_eq!(
+            out,
+            "<span font=\"A &amp; B\" face=\"&quot;C&amp;D\">t</span>"
+        );
+    }
+
+    #[test]
+    fn extracts_links() {
+        let (out, links) = process_links(
+            "<a href=\"https://a.example/?x=1&amp;y=2\">a</a> <A HREF='https://b.example'>b</A> <a>c</a>",
+            MarkupMode::Pango,
+        );
+        assert_eq!(links, ["https://a.example/?x=1&y=2", "https://b.example"]);
+        assert_eq!(
+            out,
+            "<a href=\"https://a.example/?x=1&amp;y=2\">a</a> <a href=\"https://b.example\">b</a> c"
+        );
+        let (out, links) = process_links("<a href=\"h\">x</a>", MarkupMode::Strip);
+        assert_eq!((out.as_str(), links.len()), ("x", 1));
+    }
+
+    #[test]
+    fn raw_keeps_everything() {
+        let (out, links) = process_links("<b>a</b> &amp; <a href='l'>x</a>", MarkupMode::Raw);
+        assert_eq!(out, "<b>a</b> &amp; <a href='l'>x</a>");
+        assert_eq!(links, ["l"]);
+    }
+}
@@ -0,0 +1,336 @@
+use crate::{config::Config, template::Template, Notification};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    fs::{rename, write, File, OpenOptions},
+    io::{self, Write},
+    os::fd::AsFd,
+    process::Stdio,
+    time::Duration,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::unix::pipe,
+    process::Command,
+    sync::{mpsc, watch},
+    time::timeout,
+};
+
+/// How long a command sink may take before it's killed
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How changes to the history are reported when no sinks are configured
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputMode {
+    /// Print the whole history as a JSON array, newest first, for eww's deflisten
+    Full,
+    /// Print one JSON object per change to the history
+    NdjsonEvents,
+    /// Push the whole history into an eww variable with `eww update`
+    EwwUpdate,
+}
+
+/// A single change to the history
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", content = "notification", rename_all = "lowercase")]
+pub enum Event<'a> {
+    Added(&'a Notification),
+    Removed(&'a Notification),
+    /// An entry was marked as read
+    Updated(&'a Notification),
+    Cleared,
+}
+
+/// The notifications on screen along with the history, both newest first
+#[derive(Serialize)]
+struct Combined<'a> {
+    active: Vec<&'a Notification>,
+    history: Vec<&'a Notification>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum SinkType {
+    Stdout,
+    File,
+    Fifo,
+    Command,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum SinkFormat {
+    Full,
+    NdjsonEvents,
+}
+
+/// A sink as written in the configuration file
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct SinkConfig {
+    #[serde(rename = "type")]
+    kind: SinkType,
+    path: Option<String>,
+    #[serde(default)]
+    command: Vec<String>,
+    format: Option<SinkFormat>,
+    template: Option<String>,
+}
+
+/// What a sink writes out each time the history changes
+#[derive(Debug)]
+pub enum Format {
+    /// The whole history as a JSON array, newest first
+    Full,
+    /// One JSON object per change
+    NdjsonEvents,
+    /// Every notification rendered with a template, newest first, on a single line
+    Template(Template),
+}
+
+#[derive(Debug)]
+pub enum Target {
+    Stdout,
+    /// Rewritten atomically each time, except with ndjson-events where each event is appended
+    File(String),
+    /// Written to only while something is reading from it
+    Fifo(String),
+    /// Any argument containing {} has it replaced with the output, otherwise the output is passed
+    /// on stdin. The commands are run in the background, one at a time.
+    Command(Vec<String>, Queue),
+}
+
+/// Outputs waiting for a command sink to be run with them
+#[derive(Debug)]
+pub enum Queue {
+    /// Only the newest output, since each one has the whole history
+    Latest(watch::Sender<String>),
+    /// Every output, since each one only has the events since the last
+    All(mpsc::UnboundedSender<String>),
+}
+
+#[derive(Debug)]
+pub struct Sink {
+    pub target: Target,
+    pub format: Format,
+}
+
+impl TryFrom<SinkConfig> for Sink {
+    type Error = Box<dyn Error>;
+
+    fn try_from(sink: SinkConfig) -> Result<Self, Self::Error> {
+        let format = match (sink.template, sink.format) {
+            (Some(_), Some(_)) => return Err("Sinks take either a format or a template".into()),
+            (Some(t), None) => Format::Template(t.parse()?),
+            (None, Some(SinkFormat::NdjsonEvents)) => Format::NdjsonEvents,
+            (None, _) => Format::Full,
+        };
+        let path = |kind: &str| -> Result<String, Box<dyn Error>> {
+            match sink.path.clone() {
+                Some(p) => Ok(p),
+                None => Err(format!("{} sinks need a path", kind).into()),
+            }
+        };
+        let target = match sink.kind {
+            SinkType::Stdout => Target::Stdout,
+            SinkType::File => Target::File(path("File")?),
+            SinkType::Fifo => Target::Fifo(path("FIFO")?),
+            SinkType::Command if sink.command.is_empty() => {
+                return Err("Command sinks need a command".into())
+            }
+            SinkType::Command => command_target(sink.command, &format),
+        };
+        Ok(Sink { target, format })
+    }
+}
+
+impl Sink {
+    /// The sink used when none are configured, following the older output options
+    pub fn from_mode(mode: OutputMode, eww_variable: &str, template: Option<Template>) -> Sink {
+        let format = match (mode, template) {
+            (OutputMode::NdjsonEvents, _) => Format::NdjsonEvents,
+            (_, Some(t)) => Format::Template(t),
+            (_, None) => Format::Full,
+        };
+        let target = match mode {
+            OutputMode::EwwUpdate => command_target(
+                vec![
+                    String::from("eww"),
+                    String::from("update"),
+                    format!("{}={{}}", eww_variable),
+                ],
+                &format,
+            ),
+            _ => Target::Stdout,
+        };
+        Sink { target, format }
+    }
+
+    /// Render the output for this sink. Returns None if there's nothing to write. Without any
+    /// events only the notifications on screen changed, which just the full format shows.
+    fn render(
+        &self,
+        buffer: &[Notification],
+        history: &[Notification],
+        events: &[Event],
+        track_active: bool,
+    ) -> Result<Option<String>, Box<dyn Error>> {
+        match &self.format {
+            Format::Full if track_active => {
+                let active: Vec<&Notification> = buffer.iter().rev().collect();
+                let history: Vec<&Notification> = history.iter().rev().collect();
+                Ok(Some(serde_json::to_string(&Combined { active, history })?))
+            }
+            Format::Full => Ok(Some(history_json(history)?)),
+            _ if events.is_empty() => Ok(None),
+            Format::NdjsonEvents => {
+                let mut lines = Vec::new();
+                for event in events {
+                    lines.push(serde_json::to_string(event)?);
+                }
+                Ok(Some(lines.join("\n")))
+            }
+            // Everything has to stay on one line for deflisten
+            Format::Template(template) => Ok(Some(
+                history
+                    .iter()
+                    .rev()
+                    .map(|n| template.render(n).replace('\n', " "))
+                    .collect(),
+            )),
+        }
+    }
+
+    fn write(&self, output: &str) -> Result<(), Box<dyn Error>> {
+        match &self.target {
+            Target::Stdout => println!("{}", output),
+            Target::File(path) => match self.format {
+                Format::NdjsonEvents => OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?
+                    .write_all(format!("{}\n", output).as_bytes())?,
+                _ => write_atomic(path, &format!("{}\n", output))?,
+            },
+            Target::Fifo(path) => {
+                let sender = match pipe::OpenOptions::new().open_sender(path) {
+                    Ok(s) => s,
+                    // ENXIO, nobody is reading from it
+                    Err(err) if err.raw_os_error() == Some(6) => return Ok(()),
+                    Err(err) => return Err(err.into()),
+                };
+                // The pipe is non-blocking, so a full one fails the write instead of hanging
+                let mut fifo = File::from(sender.as_fd().try_clone_to_owned()?);
+                match fifo.write_all(format!("{}\n", output).as_bytes()) {
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        return Err(format!("Reader of {} isn't keeping up", path).into())
+                    }
+                    result => result?,
+                }
+            }
+            Target::Command(command, queue) => {
+                let sent = match queue {
+                    Queue::Latest(latest) => latest.send(output.to_string()).is_ok(),
+                    Queue::All(all) => all.send(output.to_string()).is_ok(),
+                };
+                if !sent {
+                    return Err(format!("{} is no longer being run", command[0]).into());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Start running a command sink in the background, so a slow command doesn't hold up handling
+/// notifications. Outputs with the whole history replace any that are still waiting, while events
+/// are passed on in the order they came in.
+fn command_target(command: Vec<String>, format: &Format) -> Target {
+    let args = command.clone();
+    let queue = match format {
+        Format::NdjsonEvents => {
+            let (all, mut outputs) = mpsc::unbounded_channel::<String>();
+            tokio::spawn(async move {
+                while let Some(output) = outputs.recv().await {
+                    run_sink_command(&args, &output).await;
+                }
+            });
+            Queue::All(all)
+        }
+        _ => {
+            let (latest, mut outputs) = watch::channel(String::new());
+            tokio::spawn(async move {
+                while outputs.changed().await.is_ok() {
+                    let output = outputs.borrow_and_update().clone();
+                    run_sink_command(&args, &output).await;
+                }
+            });
+            Queue::Latest(latest)
+        }
+    };
+    Target::Command(command, queue)
+}
+
+async fn run_sink_command(command: &[String], output: &str) {
+    match timeout(COMMAND_TIMEOUT, run_command(command, output)).await {
+        Ok(Ok(())) => (),
+        Ok(Err(err)) => eprintln!("Failed running {}: {}", command[0], err),
+        Err(_) => eprintln!("Killed {} since it took too long", command[0]),
+    }
+}
+
+async fn run_command(command: &[String], output: &str) -> io::Result<()> {
+    let substitute = command[1..].iter().any(|a| a.contains("{}"));
+    let mut child = Command::new(&command[0])
+        .args(command[1..].iter().map(|a| a.replace("{}", output)))
+        .stdin(if substitute {
+            Stdio::null()
+        } else {
+            Stdio::piped()
+        })
+        // Dropping the child after a timeout must not leave it running
+        .kill_on_drop(true)
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(format!("{}\n", output).as_bytes()).await?;
+    }
+    let status = child.wait().await?;
+    if !status.success() {
+        return Err(io::Error::other(format!("exited with {}", status)));
+    }
+    Ok(())
+}
+
+/// Write a file through a temporary one so readers never see it half written
+pub fn write_atomic(path: &str, contents: &str) -> Result<(), Box<dyn Error>> {
+    let tmp = format!("{}.tmp", path);
+    write(&tmp, contents)?;
+    rename(&tmp, path)?;
+    Ok(())
+}
+
+/// The whole history as a JSON array, newest first
+pub fn history_json(history: &[Notification]) -> Result<String, Box<dyn Error>> {
+    let mut hist = history.to_vec();
+    hist.reverse();
+    match serde_json::to_string(&hist) {
+        Ok(j) => Ok(j),
+        Err(_) => Err("Failed history to string conversion".into()),
+    }
+}
+
+/// Report the changes that led to the current history to every sink
+pub fn emit(buffer: &[Notification], history: &[Notification], events: &[Event], config: &Config) {
+    for sink in config.sinks.iter() {
+        let result = match sink.render(buffer, history, events, config.track_active) {
+            Ok(Some(output)) => sink.write(&output),
+            Ok(None) => Ok(()),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            eprintln!("Failed writing to {:?}: {}", sink.target, err);
+        }
+    }
+}
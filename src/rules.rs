@@ -0,0 +1,190 @@
+use crate::{
+    icons::{release_icon, resolve_icon_name},
+    Config, Notification,
+};
+use regex::Regex;
+use serde::Deserialize;
+use std::{collections::HashMap, error::Error, path::Path};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Urgency {
+    Low,
+    Normal,
+    Critical,
+}
+
+impl Urgency {
+    pub fn level(&self) -> u8 {
+        match self {
+            Urgency::Low => 0,
+            Urgency::Normal => 1,
+            Urgency::Critical => 2,
+        }
+    }
+}
+
+/// A rule as written in the configuration file. The match keys are regular expressions, except
+/// for the urgency.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RuleConfig {
+    appname: Option<String>,
+    summary: Option<String>,
+    body: Option<String>,
+    category: Option<String>,
+    desktop_entry: Option<String>,
+    icon: Option<String>,
+    urgency: Option<Urgency>,
+    /// Ignore the notification altogether
+    drop: bool,
+    /// Don't add the notification to the history once it's closed
+    skip_history: bool,
+    /// Add the notification to the history right away, like dunst does when it isn't displayed
+    skip_display: bool,
+    new_icon: Option<String>,
+    set_urgency: Option<Urgency>,
+    set_stack_tag: Option<String>,
+    format: Option<String>,
+}
+
+/// What to do with notifications matching all of the given conditions
+#[derive(Debug, Default)]
+pub struct Rule {
+    pub appname: Option<Regex>,
+    pub summary: Option<Regex>,
+    pub body: Option<Regex>,
+    pub category: Option<Regex>,
+    pub desktop_entry: Option<Regex>,
+    pub icon: Option<Regex>,
+    pub urgency: Option<u8>,
+    pub drop: bool,
+    pub skip_history: bool,
+    pub skip_display: bool,
+    pub new_icon: Option<String>,
+    pub set_urgency: Option<u8>,
+    pub set_stack_tag: Option<String>,
+    pub format: Option<String>,
+}
+
+impl TryFrom<RuleConfig> for Rule {
+    type Error = Box<dyn Error>;
+
+    fn try_from(rule: RuleConfig) -> Result<Self, Self::Error> {
+        let compile = |pattern: Option<String>| -> Result<Option<Regex>, Box<dyn Error>> {
+            match pattern {
+                Some(p) => match Regex::new(&p) {
+                    Ok(r) => Ok(Some(r)),
+                    Err(err) => Err(format!("Invalid pattern {} in rule: {}", p, err).into()),
+                },
+                None => Ok(None),
+            }
+        };
+        Ok(Rule {
+            appname: compile(rule.appname)?,
+            summary: compile(rule.summary)?,
+            body: compile(rule.body)?,
+            category: compile(rule.category)?,
+            desktop_entry: compile(rule.desktop_entry)?,
+            icon: compile(rule.icon)?,
+            urgency: rule.urgency.map(|u| u.level()),
+            drop: rule.drop,
+            skip_history: rule.skip_history,
+            skip_display: rule.skip_display,
+            new_icon: rule.new_icon,
+            set_urgency: rule.set_urgency.map(|u| u.level()),
+            set_stack_tag: rule.set_stack_tag,
+            format: rule.format,
+        })
+    }
+}
+
+impl Rule {
+    pub fn matches(&self, notification: &Notification) -> bool {
+        let matches = |pattern: &Option<Regex>, value: &str| match pattern {
+            Some(p) => p.is_match(value),
+            None => true,
+        };
+        let hints = &notification.hints;
+        matches(&self.appname, &notification.appname)
+            && matches(&self.summary, &notification.summary)
+            && matches(&self.body, &notification.body)
+            && matches(&self.category, hints.category.as_deref().unwrap_or(""))
+            && matches(
+                &self.desktop_entry,
+                hints.desktop_entry.as_deref().unwrap_or(""),
+            )
+            && matches(&self.icon, &notification.app_icon)
+            && self.urgency.is_none_or(|u| u == notification.urgency)
+    }
+}
+
+/// Apply every matching rule to a new notification. Returns false if it should be dropped.
+pub fn apply_rules(
+    notification: &mut Notification,
+    cached_icons: &mut HashMap<String, i64>,
+    config: &Config,
+) -> bool {
+    for rule in config.rules.iter() {
+        if !rule.matches(notification) {
+            continue;
+        }
+        if rule.drop {
+            release_icon(cached_icons, &notification.icon);
+            return false;
+        }
+        if rule.skip_history {
+            notification.skip_history = true;
+        }
+        if let Some(icon) = &rule.new_icon {
+            let icon = resolve_icon_name(icon, cached_icons, config);
+            release_icon(cached_icons, &notification.icon);
+            notification.icon = icon;
+        }
+        if let Some(urgency) = rule.set_urgency {
+            notification.urgency = urgency;
+        }
+        if rule.skip_display {
+            notification.skip_display = true;
+        }
+        if let Some(tag) = &rule.set_stack_tag {
+            notification.stack_tag = Some(tag.clone());
+        }
+        if let Some(format) = &rule.format {
+            notification.formatted = Some(render_format(format, notification));
+        }
+    }
+    true
+}
+
+/// Render a dunst format string. Progress isn't tracked so %p and %n are left empty.
+fn render_format(format: &str, notification: &Notification) -> String {
+    let mut rendered = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            rendered.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('a') => rendered.push_str(&notification.appname),
+            Some('s') => rendered.push_str(&notification.summary),
+            Some('b') => rendered.push_str(&notification.body),
+            Some('i') => rendered.push_str(&notification.app_icon),
+            Some('I') => rendered.push_str(
+                Path::new(&notification.icon)
+                    .file_name()
+                    .and_then(|f| f.to_str())
+                    .unwrap_or(""),
+            ),
+            Some('p') | Some('n') => (),
+            Some('%') => rendered.push('%'),
+            Some(other) => {
+                rendered.push('%');
+                rendered.push(other);
+            }
+            None => rendered.push('%'),
+        }
+    }
+    rendered
+}
@@ -0,0 +1,86 @@
+use crate::control::{Message, Request};
+use tokio::sync::{mpsc, oneshot, watch};
+use zbus::{dbus_interface, fdo, Connection, ConnectionBuilder, SignalContext};
+
+pub const NAME: &str = "org.disgustang.History";
+pub const PATH: &str = "/org/disgustang/History";
+
+/// The history exposed on the session bus. Requests go through the same channel as the control
+/// socket so the main loop stays the only owner of the history.
+struct History {
+    requests: mpsc::Sender<Message>,
+}
+
+impl History {
+    async fn request(&self, request: Request) -> fdo::Result<String> {
+        let (tx, rx) = oneshot::channel();
+        if self.requests.send((request, tx)).await.is_err() {
+            return Err(fdo::Error::Failed(String::from("History is unavailable")));
+        }
+        match rx.await {
+            Ok(reply) => reply.map_err(fdo::Error::Failed),
+            Err(_) => Err(fdo::Error::Failed(String::from("History is unavailable"))),
+        }
+    }
+}
+
+#[dbus_interface(name = "org.disgustang.History")]
+impl History {
+    /// The whole history as JSON, newest first
+    async fn get_history(&self) -> fdo::Result<String> {
+        self.request(Request::History).await
+    }
+
+    /// A single notification from the history as JSON
+    async fn get_notification(&self, id: u32) -> fdo::Result<String> {
+        self.request(Request::Get(id)).await
+    }
+
+    async fn remove(&self, id: u32) -> fdo::Result<()> {
+        self.request(Request::Remove(id)).await.map(|_| ())
+    }
+
+    async fn clear(&self) -> fdo::Result<()> {
+        self.request(Request::Clear).await.map(|_| ())
+    }
+
+    /// Carries the whole history as JSON, newest first
+    #[dbus_interface(signal)]
+    async fn history_changed(ctxt: &SignalContext<'_>, history: &str) -> zbus::Result<()>;
+}
+
+/// Own the service name on a connection of its own, since the monitoring connection can't be used
+/// for anything else. HistoryChanged is emitted whenever the main loop reports a change.
+pub async fn serve(
+    requests: mpsc::Sender<Message>,
+    mut changes: watch::Receiver<()>,
+) -> zbus::Result<Connection> {
+    let connection = ConnectionBuilder::session()?
+        .name(NAME)?
+        .serve_at(
+            PATH,
+            History {
+                requests: requests.clone(),
+            },
+        )?
+        .build()
+        .await?;
+
+    let ctxt = SignalContext::new(&connection, PATH)?.into_owned();
+    tokio::spawn(async move {
+        while changes.changed().await.is_ok() {
+            let (tx, rx) = oneshot::channel();
+            if requests.send((Request::History, tx)).await.is_err() {
+                break;
+            }
+            let history = match rx.await {
+                Ok(Ok(json)) => json,
+                _ => continue,
+            };
+            if let Err(err) = History::history_changed(&ctxt, &history).await {
+                eprintln!("Failed emitting HistoryChanged: {}", err);
+            }
+        }
+    });
+    Ok(connection)
+}
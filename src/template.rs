@@ -0,0 +1,223 @@
+use crate::Notification;
+use std::str::FromStr;
+
+/// Notification fields that can be used in a template
+#[derive(Debug, Clone, Copy)]
+enum Field {
+    Id,
+    Appname,
+    Summary,
+    Body,
+    Icon,
+    Urgency,
+    Timestamp,
+    TimestampIso,
+}
+
+/// How a field is quoted
+#[derive(Debug, Clone, Copy)]
+enum Filter {
+    None,
+    /// A JSON string
+    Json,
+    /// A yuck string literal, which unlike JSON also needs `$` escaped to keep eww from
+    /// interpolating `${...}`
+    Yuck,
+}
+
+#[derive(Debug)]
+enum Piece {
+    Text(String),
+    Field(Field, Filter),
+}
+
+/// A template rendered once per notification. `{field}` inserts a field as is, `{field|json}` as
+/// a JSON string and `{field|yuck}` as a yuck string literal. Literal braces are written as `{{`
+/// and `}}`.
+#[derive(Debug)]
+pub struct Template {
+    pieces: Vec<Piece>,
+}
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        let mut pieces = Vec::new();
+        let mut text = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(format!("Unclosed {{{} in template", name)),
+                        }
+                    }
+                    let (name, filter) = match name.split_once('|') {
+                        Some((name, filter)) => match filter.trim() {
+                            "json" => (name.trim().to_string(), Filter::Json),
+                            "yuck" => (name.trim().to_string(), Filter::Yuck),
+                            filter => return Err(format!("Unknown template filter {}", filter)),
+                        },
+                        None => (name.trim().to_string(), Filter::None),
+                    };
+                    let field = match name.as_str() {
+                        "id" => Field::Id,
+                        "appname" => Field::Appname,
+                        "summary" => Field::Summary,
+                        "body" => Field::Body,
+                        "icon" => Field::Icon,
+                        "urgency" => Field::Urgency,
+                        "timestamp" => Field::Timestamp,
+                        "timestamp_iso" => Field::TimestampIso,
+                        _ => return Err(format!("Unknown template field {}", name)),
+                    };
+                    if !text.is_empty() {
+                        pieces.push(Piece::Text(std::mem::take(&mut text)));
+                    }
+                    pieces.push(Piece::Field(field, filter));
+                }
+                '}' => return Err(String::from("Unmatched } in template")),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            pieces.push(Piece::Text(text));
+        }
+        Ok(Template { pieces })
+    }
+}
+
+impl Template {
+    pub fn render(&self, notification: &Notification) -> String {
+        let mut rendered = String::new();
+        for piece in self.pieces.iter() {
+            let (field, filter) = match piece {
+                Piece::Text(t) => {
+                    rendered.push_str(t);
+                    continue;
+                }
+                Piece::Field(field, filter) => (field, *filter),
+            };
+            let value = match field {
+                Field::Id => notification.id.to_string(),
+                Field::Appname => notification.appname.clone(),
+                Field::Summary => notification.summary.clone(),
+                Field::Body => notification.body.clone(),
+                Field::Icon => notification.icon.clone(),
+                Field::Urgency => notification.urgency.to_string(),
+                Field::Timestamp => notification.timestamp.to_string(),
+                Field::TimestampIso => notification.timestamp_iso.clone(),
+            };
+            match filter {
+                Filter::None => rendered.push_str(&value),
+                Filter::Json => rendered.push_str(&serde_json::Value::String(value).to_string()),
+                Filter::Yuck => {
+                    rendered.push('"');
+                    for c in value.chars() {
+                        match c {
+                            '\\' | '"' | '$' => {
+                                rendered.push('\\');
+                                rendered.push(c);
+                            }
+                            '\n' => rendered.push(' '),
+                            c => rendered.push(c),
+                        }
+                    }
+                    rendered.push('"');
+                }
+            }
+        }
+        rendered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notification() -> Notification {
+        serde_json::from_str(
+            r#"{"serial":0,"appname":"App","summary":"Say \"hi\"","body":"a\nb","icon":"/i.png",
+                "urgency":2,"id":7,"actions":[],"sender":"","timestamp":1700000000,
+                "timestamp_iso":"2023-11-14T22:13:20Z","closed":null,"closed_iso":null,
+                "expire_timeout":-1,"close_reason":null,"hints":{}}"#,
+        )
+        .unwrap()
+    }
+
+    fn render(template: &str) -> Result<String, String> {
+        Ok(template.parse::<Template>()?.render(&notification()))
+    }
+
+    #[test]
+    fn renders_fields() {
+        assert_eq!(
+            render("{id} {appname} {summary} {icon} {urgency} {timestamp} {timestamp_iso}"),
+            Ok(String::from(
+                "7 App Say \"hi\" /i.png 2 1700000000 2023-11-14T22:13:20Z"
+            ))
+        );
+        assert_eq!(render("{ body }"), Ok(String::from("a\nb")));
+        assert_eq!(render(""), Ok(String::new()));
+    }
+
+    #[test]
+    fn json_filter_quotes_and_escapes() {
+        assert_eq!(
+            render("(label :text {summary|json})"),
+            Ok(String::from("(label :text \"Say \\\"hi\\\"\")"))
+        );
+        assert_eq!(render("{body | json}"), Ok(String::from("\"a\\nb\"")));
+    }
+
+    #[test]
+    fn yuck_filter_escapes_interpolation() {
+        let mut n = notification();
+        n.summary = String::from("Costs ${price} \\o/ \"now\"");
+        let template: Template = "(label :text {summary|yuck})".parse().unwrap();
+        assert_eq!(
+            template.render(&n),
+            "(label :text \"Costs \\${price} \\\\o/ \\\"now\\\"\")"
+        );
+        assert_eq!(render("{body|yuck}"), Ok(String::from("\"a b\"")));
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        assert_eq!(render("{{{id}}}"), Ok(String::from("{7}")));
+        assert_eq!(render("}}{{"), Ok(String::from("}{")));
+    }
+
+    #[test]
+    fn rejects_bad_templates() {
+        assert_eq!(
+            render("{nope}"),
+            Err(String::from("Unknown template field nope"))
+        );
+        assert_eq!(
+            render("{summary|upper}"),
+            Err(String::from("Unknown template filter upper"))
+        );
+        assert_eq!(
+            render("x {summary"),
+            Err(String::from("Unclosed {summary in template"))
+        );
+        assert_eq!(
+            render("a } b"),
+            Err(String::from("Unmatched } in template"))
+        );
+    }
+}